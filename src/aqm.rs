@@ -0,0 +1,145 @@
+use std::{cell::Cell, time::Duration};
+
+/// Weight of a new sample in the moving average of the latency, as a right shift.
+const EWMA_SHIFT: u32 = 3;
+
+/// Controller limiting the amount of IOs in flight to the target, to keep the latency of the
+/// target below a configured value.
+///
+/// The limit is adjusted once per window of `limit` completions: it is halved if the average
+/// latency exceeded the target during the window, and increased by 1 otherwise. This keeps the
+/// target saturated when it is fast, while preventing large queues from building up in the
+/// target (which mostly inflate tail latency) when it is slow. A controller belongs to a single
+/// queue, and is not thread safe.
+#[derive(Debug)]
+pub struct DepthController {
+    /// Target latency of a single IO.
+    target: Duration,
+    /// Upper bound of the limit, usually the queue depth.
+    max_depth: u32,
+    /// Current limit of IOs in flight.
+    limit: Cell<u32>,
+    /// Amount of IOs currently in flight.
+    in_flight: Cell<u32>,
+    /// Completions seen in the current window.
+    completions: Cell<u32>,
+    /// Exponentially weighted moving average of the latency, in nanoseconds.
+    avg_latency: Cell<u64>,
+}
+
+impl DepthController {
+    pub fn new(target: Duration, max_depth: u32) -> DepthController {
+        DepthController {
+            target,
+            max_depth,
+            limit: Cell::new(max_depth),
+            in_flight: Cell::new(0),
+            completions: Cell::new(0),
+            avg_latency: Cell::new(0),
+        }
+    }
+
+    /// Try to claim a slot for a new IO. If this returns true, [`Self::release`] must be called
+    /// once the IO finishes.
+    pub fn try_acquire(&self) -> bool {
+        let in_flight = self.in_flight.get();
+        if in_flight >= self.limit.get() {
+            return false;
+        }
+        self.in_flight.set(in_flight + 1);
+        true
+    }
+
+    /// Release the slot of a finished IO, which took the given time to complete.
+    pub fn release(&self, latency: Duration) {
+        self.in_flight.set(self.in_flight.get() - 1);
+
+        let sample = latency.as_nanos().min(u64::MAX as u128) as u64;
+        let avg = self.avg_latency.get();
+        let avg = if avg == 0 {
+            sample
+        } else {
+            avg - (avg >> EWMA_SHIFT) + (sample >> EWMA_SHIFT)
+        };
+        self.avg_latency.set(avg);
+
+        let completions = self.completions.get() + 1;
+        let limit = self.limit.get();
+        if completions < limit {
+            self.completions.set(completions);
+            return;
+        }
+        self.completions.set(0);
+
+        let limit = if avg > self.target.as_nanos() as u64 {
+            (limit / 2).max(1)
+        } else {
+            (limit + 1).min(self.max_depth)
+        };
+        if limit != self.limit.get() {
+            log::debug!("adjusting target queue depth to {limit}, average latency {avg}ns");
+        }
+        self.limit.set(limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: Duration = Duration::from_millis(1);
+
+    /// Run a full window of IOs with the given latency.
+    fn window(controller: &DepthController, latency: Duration) {
+        let limit = controller.limit.get();
+        for _ in 0..limit {
+            assert!(controller.try_acquire());
+        }
+        assert!(!controller.try_acquire());
+        for _ in 0..limit {
+            controller.release(latency);
+        }
+    }
+
+    #[test]
+    fn halve_when_slow() {
+        let controller = DepthController::new(TARGET, 8);
+        window(&controller, TARGET * 2);
+        assert_eq!(controller.limit.get(), 4);
+        window(&controller, TARGET * 2);
+        assert_eq!(controller.limit.get(), 2);
+        window(&controller, TARGET * 2);
+        window(&controller, TARGET * 2);
+        // At least one IO is always allowed.
+        assert_eq!(controller.limit.get(), 1);
+    }
+
+    #[test]
+    fn grow_when_fast() {
+        let controller = DepthController::new(TARGET, 8);
+        window(&controller, TARGET * 2);
+        window(&controller, TARGET * 2);
+        assert_eq!(controller.limit.get(), 2);
+
+        // The average needs a few fast samples to drop below the target.
+        for _ in 0..20 {
+            window(&controller, Duration::ZERO);
+        }
+        assert_eq!(controller.limit.get(), 8);
+        window(&controller, Duration::ZERO);
+        assert_eq!(controller.limit.get(), 8);
+    }
+
+    #[test]
+    fn adjust_once_per_window() {
+        let controller = DepthController::new(TARGET, 4);
+        assert!(controller.try_acquire());
+        controller.release(TARGET * 2);
+        assert_eq!(controller.limit.get(), 4);
+        for _ in 0..3 {
+            assert!(controller.try_acquire());
+            controller.release(TARGET * 2);
+        }
+        assert_eq!(controller.limit.get(), 2);
+    }
+}
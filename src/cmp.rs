@@ -0,0 +1,149 @@
+use std::{fs::File, io, ops::Range, os::fd::AsRawFd, os::unix::prelude::FileExt};
+
+use nix::unistd::{lseek, Whence};
+
+/// Amount of bytes read from both sides at once.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// One side of a comparison.
+pub struct Source<'a> {
+    pub file: &'a File,
+    pub size: u64,
+    /// Sorted ranges which hold data, everything else reads as zeroes. If this is not known
+    /// (e.g. for a device which is not thin provisioned), the holes are looked up with
+    /// SEEK_DATA, which finds none on block devices.
+    pub data: Option<Vec<Range<u64>>>,
+}
+
+/// Compare the contents of 2 files or block devices, returning the byte ranges which differ.
+///
+/// Ranges are reported with a granularity of `block_size`, adjacent differing blocks are merged
+/// in a single range. If one side is larger than the other, the excess is reported as differing.
+/// Regions which are holes on both sides (e.g. unallocated regions of sparse files or chunks of
+/// a thin device which were never written) are equal by definition and are skipped without
+/// reading them.
+pub fn compare(a: &Source, b: &Source, block_size: u64) -> io::Result<Vec<Range<u64>>> {
+    let (a_size, b_size) = (a.size, b.size);
+    let common = a_size.min(b_size);
+    let chunk_size = CHUNK_SIZE.next_multiple_of(block_size);
+    let mut a_buf = vec![0; chunk_size as usize];
+    let mut b_buf = vec![0; chunk_size as usize];
+    let mut differences: Vec<Range<u64>> = Vec::new();
+
+    let mut off = 0;
+    while off < common {
+        let data = next_data(a, off, common)
+            .min(next_data(b, off, common))
+            .min(common);
+        let data = data - data % block_size;
+        if data > off {
+            off = data;
+            continue;
+        }
+
+        let len = chunk_size.min(common - off) as usize;
+        a.file.read_exact_at(&mut a_buf[..len], off)?;
+        b.file.read_exact_at(&mut b_buf[..len], off)?;
+
+        for (i, (a_block, b_block)) in a_buf[..len]
+            .chunks(block_size as usize)
+            .zip(b_buf[..len].chunks(block_size as usize))
+            .enumerate()
+        {
+            if a_block == b_block {
+                continue;
+            }
+            let start = off + i as u64 * block_size;
+            let end = start + a_block.len() as u64;
+            match differences.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => differences.push(start..end),
+            }
+        }
+
+        off += len as u64;
+    }
+
+    if a_size != b_size {
+        let end = a_size.max(b_size);
+        match differences.last_mut() {
+            Some(last) if last.end == common => last.end = end,
+            _ => differences.push(common..end),
+        }
+    }
+
+    Ok(differences)
+}
+
+/// Find the start of the next region containing data at or after `off`. If the underlying
+/// filesystem does not support finding holes, `off` itself is returned, so the whole file is
+/// treated as data. This is always the case for block devices without a list of data ranges.
+fn next_data(source: &Source, off: u64, size: u64) -> u64 {
+    if let Some(data) = &source.data {
+        return match data.iter().find(|range| range.end > off) {
+            Some(range) => range.start.max(off),
+            None => size,
+        };
+    }
+    match lseek(source.file.as_raw_fd(), off as i64, Whence::SeekData) {
+        Ok(data) => data as u64,
+        // No more data after off.
+        Err(nix::Error::ENXIO) => size,
+        Err(_) => off,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_ranges_skip_holes() {
+        let file = File::open("/dev/null").unwrap();
+        let source = Source {
+            file: &file,
+            size: 1 << 20,
+            data: Some(vec![4096..8192, 65536..131072]),
+        };
+        assert_eq!(next_data(&source, 0, 1 << 20), 4096);
+        assert_eq!(next_data(&source, 5000, 1 << 20), 5000);
+        assert_eq!(next_data(&source, 8192, 1 << 20), 65536);
+        assert_eq!(next_data(&source, 131072, 1 << 20), 1 << 20);
+    }
+
+    #[test]
+    fn holes_on_both_sides_are_equal() {
+        let path = std::env::temp_dir().join(format!("vblock-cmp-{}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(4 << 20).unwrap();
+        file.write_all_at(&[1; 512], (2 << 20) + 8192).unwrap();
+
+        // Only the chunks with the ranges listed as data of a are read, so the write between
+        // them is not seen.
+        let a = Source {
+            file: &file,
+            size: 4 << 20,
+            data: Some(vec![0..4096, 3 << 20..(3 << 20) + 4096]),
+        };
+        let zeroes = File::open("/dev/zero").unwrap();
+        let b = Source {
+            file: &zeroes,
+            size: 4 << 20,
+            data: Some(Vec::new()),
+        };
+        assert!(compare(&a, &b, 4096).unwrap().is_empty());
+
+        let a = Source { data: None, ..a };
+        assert_eq!(
+            compare(&a, &b, 4096).unwrap(),
+            vec![(2 << 20) + 8192..(2 << 20) + 12288]
+        );
+    }
+}
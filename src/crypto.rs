@@ -0,0 +1,212 @@
+use std::{
+    fs,
+    io::{self, IoSlice, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::PermissionsExt,
+    },
+    path::Path,
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, KeyInit},
+    Aes256,
+};
+use nix::sys::socket::{
+    accept, bind, sendmsg, setsockopt, socket, sockopt::AlgSetKey, AddressFamily, AlgAddr,
+    ControlMessage, MsgFlags, SockFlag, SockType,
+};
+use xts_mode::{get_tweak_default, Xts128};
+
+/// Size of a single encrypted data unit. Every sector is encrypted with its sector number as
+/// tweak.
+const SECTOR_SIZE: usize = 512;
+
+/// Size of a key: two AES-256 keys, one for the data and one for the tweak.
+pub const KEY_SIZE: usize = 64;
+
+/// Directory holding named keys, every file in it is a raw key named after the file.
+const KEY_DIR: &str = "/etc/vblock/keys";
+
+/// Key used if explicitly requested instead of a real one. This is public, so data encrypted with
+/// it is not protected, it is only meant for testing.
+pub const TEST_KEY: [u8; KEY_SIZE] = {
+    let mut key = [0; KEY_SIZE];
+    let mut i = 0;
+    while i < KEY_SIZE {
+        key[i] = i as u8;
+        i += 1;
+    }
+    key
+};
+
+/// AF_ALG operation to decrypt data, defined in linux/if_alg.h
+const ALG_OP_DECRYPT: i32 = 0;
+/// AF_ALG operation to encrypt data, defined in linux/if_alg.h
+const ALG_OP_ENCRYPT: i32 = 1;
+
+/// Encryption of data stored on the target.
+///
+/// Both implementations use AES-256-XTS with the same key layout and tweaks, so they produce the
+/// same ciphertext, and a device can switch between them.
+pub enum Cipher {
+    /// Encryption in userspace.
+    Software(Box<Xts128<Aes256>>),
+    /// Encryption with the kernel crypto API through AF_ALG. This uses hardware offload or
+    /// certified implementations registered with the kernel, at the cost of 2 syscalls per
+    /// sector.
+    Kernel(KernelCipher),
+}
+
+/// Handle to an AES-XTS transform in the kernel crypto API.
+pub struct KernelCipher {
+    tfm: OwnedFd,
+}
+
+/// Which implementation of [`Cipher`] to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherBackend {
+    /// See [`Cipher::Software`].
+    Software,
+    /// See [`Cipher::Kernel`].
+    Kernel,
+}
+
+impl Cipher {
+    /// Set up a cipher with the given backend. The first half of the key is used for the data,
+    /// the second half for the tweak.
+    pub fn new(backend: CipherBackend, key: &[u8; KEY_SIZE]) -> io::Result<Cipher> {
+        match backend {
+            CipherBackend::Software => {
+                let cipher_1 = Aes256::new(GenericArray::from_slice(&key[..KEY_SIZE / 2]));
+                let cipher_2 = Aes256::new(GenericArray::from_slice(&key[KEY_SIZE / 2..]));
+                Ok(Cipher::Software(Box::new(Xts128::new(cipher_1, cipher_2))))
+            }
+            CipherBackend::Kernel => Ok(Cipher::Kernel(KernelCipher::new(key)?)),
+        }
+    }
+
+    /// Encrypt buf in place. The buffer starts at the given sector, and its length must be a
+    /// multiple of the sector size.
+    pub fn encrypt_area(&self, buf: &mut [u8], start_sector: u64) -> io::Result<()> {
+        match self {
+            Cipher::Software(xts) => {
+                xts.encrypt_area(buf, SECTOR_SIZE, start_sector as u128, get_tweak_default);
+                Ok(())
+            }
+            Cipher::Kernel(kc) => kc.crypt(buf, start_sector, ALG_OP_ENCRYPT),
+        }
+    }
+
+    /// Decrypt buf in place. The buffer starts at the given sector, and its length must be a
+    /// multiple of the sector size.
+    pub fn decrypt_area(&self, buf: &mut [u8], start_sector: u64) -> io::Result<()> {
+        match self {
+            Cipher::Software(xts) => {
+                xts.decrypt_area(buf, SECTOR_SIZE, start_sector as u128, get_tweak_default);
+                Ok(())
+            }
+            Cipher::Kernel(kc) => kc.crypt(buf, start_sector, ALG_OP_DECRYPT),
+        }
+    }
+}
+
+/// Load a key from a file holding exactly [`KEY_SIZE`] raw bytes, e.g. created with
+/// `head -c 64 /dev/urandom`.
+pub fn load_key(path: &Path) -> io::Result<[u8; KEY_SIZE]> {
+    parse_key(fs::read(path)?)
+}
+
+/// Load a named key from [`KEY_DIR`]. The key file must not be accessible by other users than
+/// its owner.
+pub fn load_named_key(name: &str) -> io::Result<[u8; KEY_SIZE]> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "key name must not be empty, start with a dot or contain a slash",
+        ));
+    }
+    let path = Path::new(KEY_DIR).join(name);
+    let file = fs::File::open(&path)?;
+    if file.metadata()?.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is accessible by other users", path.display()),
+        ));
+    }
+    let mut data = Vec::with_capacity(KEY_SIZE);
+    (&file).read_to_end(&mut data)?;
+    parse_key(data)
+}
+
+/// Load a key from an environment variable holding [`KEY_SIZE`] hex encoded bytes. The variable
+/// is removed, so it is not passed on to other processes.
+pub fn load_key_env(name: &str) -> io::Result<[u8; KEY_SIZE]> {
+    let hex = std::env::var(name).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    std::env::remove_var(name);
+    let hex = hex.trim();
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "key is not valid hex"))?;
+    parse_key(data)
+}
+
+/// Check that data is a usable key.
+fn parse_key(data: Vec<u8>) -> io::Result<[u8; KEY_SIZE]> {
+    let key: [u8; KEY_SIZE] = data.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("key must be exactly {KEY_SIZE} bytes"),
+        )
+    })?;
+    // XTS is not secure if both halves are equal, and the kernel rejects such keys in FIPS mode.
+    if key[..KEY_SIZE / 2] == key[KEY_SIZE / 2..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "both halves of the key are equal",
+        ));
+    }
+    Ok(key)
+}
+
+impl KernelCipher {
+    fn new(key: &[u8; KEY_SIZE]) -> io::Result<KernelCipher> {
+        let tfm = socket(
+            AddressFamily::Alg,
+            SockType::SeqPacket,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        bind(tfm.as_raw_fd(), &AlgAddr::new("skcipher", "xts(aes)"))?;
+        setsockopt(&tfm, AlgSetKey::default(), key)?;
+
+        Ok(KernelCipher { tfm })
+    }
+
+    /// Run an operation on every sector in buf.
+    fn crypt(&self, buf: &mut [u8], start_sector: u64, op: i32) -> io::Result<()> {
+        // Every call gets its own operation socket, so queues can use the cipher concurrently.
+        // SAFETY: accept returns a new file descriptor which is owned by nothing else.
+        let op_fd = unsafe { OwnedFd::from_raw_fd(accept(self.tfm.as_raw_fd())?) };
+
+        for (sector, chunk) in (start_sector..).zip(buf.chunks_exact_mut(SECTOR_SIZE)) {
+            let iv = get_tweak_default(sector as u128);
+            let cmsgs = [ControlMessage::AlgSetOp(&op), ControlMessage::AlgSetIv(&iv)];
+            sendmsg::<AlgAddr>(
+                op_fd.as_raw_fd(),
+                &[IoSlice::new(chunk)],
+                &cmsgs,
+                MsgFlags::empty(),
+                None,
+            )?;
+            let n = nix::unistd::read(op_fd.as_raw_fd(), chunk)?;
+            if n != SECTOR_SIZE {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(())
+    }
+}
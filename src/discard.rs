@@ -0,0 +1,227 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    ops::Range,
+    str::FromStr,
+    time::Duration,
+};
+
+use nix::fcntl::FallocateFlags;
+
+pub use crate::flush::Role;
+
+/// Discards of at least this size are not batched, merging them gains little.
+pub const BATCH_MAX_BYTES: u64 = 1 << 20;
+
+/// Placeholder result of a batch which is still being discarded. This is not a valid result of
+/// a discard, which returns 0 or a negative errno.
+const EPENDING: i32 = i32::MIN;
+
+/// How discard requests on the virtual device are handled.
+///
+/// Mounting a filesystem with `-o discard` can generate a lot of small discards, which can be
+/// costly on slow targets. Depending on the target, it might be better to drop them entirely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscardPolicy {
+    /// Complete discards without touching the target.
+    Ignore,
+    /// Deallocate the range in the target, so a file target becomes sparse again. The hole is
+    /// decrypted as any other data, so the range reads back as garbage.
+    PunchHole,
+    /// Issue a BLKDISCARD on the target, which must be a block device. What the range reads back
+    /// as depends on the target.
+    Passdown,
+    /// Overwrite the range with zeroes, encrypted like any other data, so the range reads back
+    /// as zeroes.
+    Zero,
+}
+
+impl DiscardPolicy {
+    /// The fallocate mode implementing this policy, if it is implemented with fallocate.
+    pub fn fallocate_mode(self) -> Option<FallocateFlags> {
+        match self {
+            DiscardPolicy::PunchHole => {
+                Some(FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE)
+            }
+            DiscardPolicy::Ignore | DiscardPolicy::Passdown | DiscardPolicy::Zero => None,
+        }
+    }
+}
+
+/// Batches small discards arriving within a short window, so adjacent ones reach the target as a
+/// single discard.
+///
+/// The first discard opens a batch and waits for the window to pass. Discards arriving in the
+/// meantime join the batch, and complete with its result once the merged ranges are discarded.
+/// Like a [`crate::flush::FlushCoalescer`], a batcher belongs to a single queue, and is not
+/// thread safe.
+#[derive(Debug)]
+pub struct DiscardBatcher {
+    /// Time the first discard of a batch waits for others to join.
+    window: Duration,
+    /// Batch accepting new discards.
+    open: RefCell<Option<Batch>>,
+    /// Id of the next batch.
+    next_batch: Cell<u64>,
+    /// Result of finished batches, and the amount of joined discards which did not pick it up
+    /// yet.
+    results: RefCell<HashMap<u64, (i32, usize)>>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    id: u64,
+    ranges: Vec<Range<u64>>,
+    /// Amount of discards which joined the batch, besides the one which opened it.
+    joined: usize,
+}
+
+impl DiscardBatcher {
+    pub fn new(window: Duration) -> DiscardBatcher {
+        DiscardBatcher {
+            window,
+            open: RefCell::new(None),
+            next_batch: Cell::new(0),
+            results: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Time the first discard of a batch waits for others to join.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Add the range of a discard to the open batch, or open a new one with it.
+    pub fn join(&self, range: Range<u64>) -> Role {
+        let mut open = self.open.borrow_mut();
+        match open.as_mut() {
+            Some(batch) => {
+                batch.ranges.push(range);
+                batch.joined += 1;
+                Role::Join(batch.id)
+            }
+            None => {
+                let id = self.next_batch.get();
+                self.next_batch.set(id + 1);
+                *open = Some(Batch {
+                    id,
+                    ranges: vec![range],
+                    joined: 0,
+                });
+                Role::Lead(id)
+            }
+        }
+    }
+
+    /// Stop accepting discards in a batch, returning its ranges sorted, with overlapping and
+    /// adjacent ones merged.
+    pub fn close(&self, batch: u64) -> Vec<Range<u64>> {
+        let mut open = self.open.borrow_mut();
+        let Some(Batch {
+            mut ranges, joined, ..
+        }) = open.take_if(|open| open.id == batch)
+        else {
+            return Vec::new();
+        };
+        if joined > 0 {
+            // Joined discards wait for this entry, a failed discard must not be lost.
+            self.results.borrow_mut().insert(batch, (EPENDING, joined));
+        }
+
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    /// Record the result of discarding a batch.
+    pub fn finish(&self, batch: u64, res: i32) {
+        if let Some((result, _)) = self.results.borrow_mut().get_mut(&batch) {
+            *result = res;
+        }
+    }
+
+    /// Result of the batch a discard joined, if it was discarded.
+    pub fn result(&self, batch: u64) -> Option<i32> {
+        let mut results = self.results.borrow_mut();
+        let (res, waiting) = results.get_mut(&batch)?;
+        if *res == EPENDING {
+            return None;
+        }
+        let res = *res;
+        *waiting -= 1;
+        if *waiting == 0 {
+            results.remove(&batch);
+        }
+        Some(res)
+    }
+}
+
+/// Error returned when parsing an unknown [`DiscardPolicy`].
+#[derive(Debug, Clone)]
+pub struct UnknownPolicy(String);
+
+impl FromStr for DiscardPolicy {
+    type Err = UnknownPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(DiscardPolicy::Ignore),
+            "punch-hole" => Ok(DiscardPolicy::PunchHole),
+            "passdown" => Ok(DiscardPolicy::Passdown),
+            "zero" => Ok(DiscardPolicy::Zero),
+            _ => Err(UnknownPolicy(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for UnknownPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("unknown discard policy {}", self.0))
+    }
+}
+
+impl std::error::Error for UnknownPolicy {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_adjacent_discards() {
+        let batcher = DiscardBatcher::new(Duration::from_millis(1));
+        assert_eq!(batcher.join(8192..12288), Role::Lead(0));
+        assert_eq!(batcher.join(0..4096), Role::Join(0));
+        assert_eq!(batcher.join(4096..8192), Role::Join(0));
+        assert_eq!(batcher.join(65536..69632), Role::Join(0));
+        assert_eq!(batcher.join(66048..67072), Role::Join(0));
+        assert_eq!(batcher.close(0), vec![0..12288, 65536..69632]);
+        // Discards arriving after the batch closed open a new one.
+        assert_eq!(batcher.join(0..4096), Role::Lead(1));
+
+        assert_eq!(batcher.result(0), None);
+        batcher.finish(0, -5);
+        for _ in 0..4 {
+            assert_eq!(batcher.result(0), Some(-5));
+        }
+        // Every joined discard picked up the result.
+        assert_eq!(batcher.result(0), None);
+    }
+
+    #[test]
+    fn close_other_batch() {
+        let batcher = DiscardBatcher::new(Duration::from_millis(1));
+        assert_eq!(batcher.join(0..4096), Role::Lead(0));
+        assert_eq!(batcher.close(1), Vec::new());
+        assert_eq!(batcher.join(4096..8192), Role::Join(0));
+        assert_eq!(batcher.close(0), vec![0..8192]);
+        batcher.finish(0, 0);
+        assert_eq!(batcher.result(0), Some(0));
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Directory holding the error maps of running devices.
+const RUN_DIR: &str = "/run/vblock";
+
+/// Extents of a device for which the targets returned an unrecoverable media error.
+///
+/// The map is kept in memory, and written to [`path`] on every change, so it can be inspected
+/// while the device runs. Extents are in bytes on the device, overlapping and adjacent extents
+/// are merged. Rewriting an extent successfully removes it from the map.
+#[derive(Debug)]
+pub struct ErrorMap {
+    /// Start of every extent, mapped to its end.
+    extents: Mutex<BTreeMap<u64, u64>>,
+    path: PathBuf,
+}
+
+impl ErrorMap {
+    /// Create an empty error map for device `id`. A map left behind by an earlier device with
+    /// the same id is removed.
+    pub fn new(id: u32) -> ErrorMap {
+        let path = path(id);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("could not remove stale error map {}: {e}", path.display());
+            }
+        }
+        ErrorMap {
+            extents: Mutex::new(BTreeMap::new()),
+            path,
+        }
+    }
+
+    /// Record an error for `len` bytes at `start`.
+    pub fn record(&self, start: u64, len: u64) {
+        let mut extents = self.extents.lock().unwrap();
+        let Range { start, end } = insert(&mut extents, start..start + len);
+
+        log::error!("unrecoverable error in extent {start}..{end}");
+        if let Err(e) = store(&self.path, &extents) {
+            log::warn!("could not store error map {}: {e}", self.path.display());
+        }
+    }
+
+    /// Forget the errors for `len` bytes at `start`, as they were written successfully. Extents
+    /// partially covered by the range are trimmed or split.
+    pub fn clear(&self, start: u64, len: u64) {
+        let mut extents = self.extents.lock().unwrap();
+        let end = start + len;
+        if !remove(&mut extents, start..end) {
+            return;
+        }
+
+        log::info!("extent {start}..{end} was rewritten, clearing its errors");
+        if let Err(e) = store(&self.path, &extents) {
+            log::warn!("could not store error map {}: {e}", self.path.display());
+        }
+    }
+
+    /// Whether any part of `len` bytes at `start` is in a known bad extent.
+    pub fn contains(&self, start: u64, len: u64) -> bool {
+        overlaps(&self.extents.lock().unwrap(), start..start + len)
+    }
+}
+
+/// Add a range to a set of extents, merging it with the overlapping and adjacent ones. Returns
+/// the merged extent.
+fn insert(extents: &mut BTreeMap<u64, u64>, range: Range<u64>) -> Range<u64> {
+    let Range { mut start, mut end } = range;
+    // Extents don't overlap, so the ones touching the new extent are the last ones starting at
+    // or before its end.
+    let touching: Vec<u64> = extents
+        .range(..=end)
+        .rev()
+        .take_while(|(_, e)| **e >= start)
+        .map(|(s, _)| *s)
+        .collect();
+    for s in touching {
+        let e = extents.remove(&s).unwrap();
+        start = start.min(s);
+        end = end.max(e);
+    }
+    extents.insert(start, end);
+    start..end
+}
+
+/// Remove a range from a set of extents, trimming or splitting the ones partially covered by it.
+/// Returns whether any extent changed.
+fn remove(extents: &mut BTreeMap<u64, u64>, range: Range<u64>) -> bool {
+    let Range { start, end } = range;
+    let overlapping: Vec<(u64, u64)> = extents
+        .range(..end)
+        .rev()
+        .take_while(|(_, e)| **e > start)
+        .map(|(s, e)| (*s, *e))
+        .collect();
+    for &(s, e) in &overlapping {
+        extents.remove(&s);
+        if s < start {
+            extents.insert(s, start);
+        }
+        if e > end {
+            extents.insert(end, e);
+        }
+    }
+    !overlapping.is_empty()
+}
+
+/// Whether any part of a range is in a set of extents.
+fn overlaps(extents: &BTreeMap<u64, u64>, range: Range<u64>) -> bool {
+    extents
+        .range(..range.end)
+        .next_back()
+        .is_some_and(|(_, end)| *end > range.start)
+}
+
+/// Path of the error map of device `id`.
+pub fn path(id: u32) -> PathBuf {
+    Path::new(RUN_DIR).join(format!("ublkb{id}.errors"))
+}
+
+/// Ids of the devices with an error map.
+pub fn ids() -> io::Result<Vec<u32>> {
+    let entries = match fs::read_dir(RUN_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut ids = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("ublkb"))
+            .and_then(|name| name.strip_suffix(".errors"))
+            .and_then(|id| id.parse::<u32>().ok());
+        ids.extend(id);
+    }
+    Ok(ids)
+}
+
+/// Load the error map of device `id`. A device without errors has no map.
+pub fn load(id: u32) -> io::Result<Vec<Range<u64>>> {
+    let data = match fs::read_to_string(path(id)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    data.lines()
+        .map(|line| {
+            let (start, end) = line
+                .split_once(' ')
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid extent"))?;
+            let parse = |v: &str| {
+                v.parse::<u64>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            };
+            Ok(parse(start)?..parse(end)?)
+        })
+        .collect()
+}
+
+/// Write the extents to path, one `start end` pair per line. The map is replaced atomically, so
+/// readers never see a partial map.
+fn store(path: &Path, extents: &BTreeMap<u64, u64>) -> io::Result<()> {
+    fs::create_dir_all(RUN_DIR)?;
+    let data: String = extents
+        .iter()
+        .map(|(start, end)| format!("{start} {end}\n"))
+        .collect();
+    let tmp = path.with_extension("errors.tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extents(ranges: &[(u64, u64)]) -> BTreeMap<u64, u64> {
+        ranges.iter().copied().collect()
+    }
+
+    #[test]
+    fn insert_merges() {
+        let mut map = BTreeMap::new();
+        assert_eq!(insert(&mut map, 10..20), 10..20);
+        assert_eq!(insert(&mut map, 30..40), 30..40);
+        assert_eq!(map, extents(&[(10, 20), (30, 40)]));
+        // Adjacent extents are merged.
+        assert_eq!(insert(&mut map, 20..25), 10..25);
+        // An extent overlapping several merges all of them.
+        assert_eq!(insert(&mut map, 5..35), 5..40);
+        assert_eq!(map, extents(&[(5, 40)]));
+        assert_eq!(insert(&mut map, 50..60), 50..60);
+        assert_eq!(insert(&mut map, 10..12), 5..40);
+        assert_eq!(map, extents(&[(5, 40), (50, 60)]));
+    }
+
+    #[test]
+    fn remove_trims_and_splits() {
+        let mut map = extents(&[(10, 20), (30, 40), (50, 60)]);
+        assert!(!remove(&mut map, 20..30));
+        assert!(!remove(&mut map, 0..10));
+        // Trim the end of one extent and the start of the next.
+        assert!(remove(&mut map, 15..35));
+        assert_eq!(map, extents(&[(10, 15), (35, 40), (50, 60)]));
+        // Split an extent.
+        assert!(remove(&mut map, 53..57));
+        assert_eq!(map, extents(&[(10, 15), (35, 40), (50, 53), (57, 60)]));
+        // Remove whole extents.
+        assert!(remove(&mut map, 0..100));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let map = extents(&[(10, 20), (30, 40)]);
+        assert!(!overlaps(&map, 0..10));
+        assert!(overlaps(&map, 0..11));
+        assert!(overlaps(&map, 19..30));
+        assert!(!overlaps(&map, 20..30));
+        assert!(overlaps(&map, 35..36));
+        assert!(!overlaps(&map, 40..100));
+        assert!(overlaps(&map, 0..100));
+    }
+}
@@ -0,0 +1,36 @@
+use std::{fs::File, io, os::unix::prelude::FileExt};
+
+/// Amount of bytes read from the source at once.
+const CHUNK_SIZE: usize = 1 << 20;
+/// Granularity at which zeroes are detected and left as holes in the output.
+const HOLE_SIZE: usize = 4096;
+
+/// Copy `size` bytes from `src` into `dst`, which should be an empty regular file. Blocks which
+/// only contain zeroes are not written, so they end up as holes in the output, and the output
+/// takes no more space than the data actually stored. Returns the amount of bytes written.
+pub fn flatten(src: &File, size: u64, dst: &File) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+
+    let mut off = 0;
+    while off < size {
+        let len = CHUNK_SIZE.min((size - off) as usize);
+        src.read_exact_at(&mut buf[..len], off)?;
+
+        for (i, block) in buf[..len].chunks(HOLE_SIZE).enumerate() {
+            if block.iter().all(|b| *b == 0) {
+                continue;
+            }
+            dst.write_all_at(block, off + (i * HOLE_SIZE) as u64)?;
+            written += block.len() as u64;
+        }
+
+        off += len as u64;
+    }
+
+    // Trailing holes are not written, so extend the file to the full size.
+    dst.set_len(size)?;
+    dst.sync_all()?;
+
+    Ok(written)
+}
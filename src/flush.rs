@@ -0,0 +1,148 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    time::Duration,
+};
+
+/// Placeholder result of a batch whose sync is still running. This is not a valid result of a
+/// sync, which returns 0 or a negative errno.
+const EPENDING: i32 = i32::MIN;
+
+/// Role of a flush in a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The flush opened the batch. It waits for the window to pass, syncs the targets, and
+    /// reports the result with [`FlushCoalescer::finish`].
+    Lead(u64),
+    /// The flush joined an open batch, and completes with the result of its sync.
+    Join(u64),
+}
+
+/// Coalesces flushes arriving within a short window into a single sync of the targets.
+///
+/// The first flush opens a batch and waits for the window to pass. Flushes arriving in the
+/// meantime join the batch, and the batch is closed right before its sync is submitted, so a
+/// flush is only ever completed by a sync which started after it arrived. A coalescer belongs to
+/// a single queue, and is not thread safe.
+#[derive(Debug)]
+pub struct FlushCoalescer {
+    /// Time the first flush of a batch waits for others to join.
+    window: Duration,
+    /// Batch accepting new flushes, and the amount of flushes which joined it.
+    open: Cell<Option<(u64, usize)>>,
+    /// Id of the next batch.
+    next_batch: Cell<u64>,
+    /// Result of finished batches, and the amount of joined flushes which did not pick it up yet.
+    results: RefCell<HashMap<u64, (i32, usize)>>,
+}
+
+impl FlushCoalescer {
+    pub fn new(window: Duration) -> FlushCoalescer {
+        FlushCoalescer {
+            window,
+            open: Cell::new(None),
+            next_batch: Cell::new(0),
+            results: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Time the first flush of a batch waits for others to join.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Add a flush to the open batch, or open a new one.
+    pub fn join(&self) -> Role {
+        match self.open.get() {
+            Some((batch, joined)) => {
+                self.open.set(Some((batch, joined + 1)));
+                Role::Join(batch)
+            }
+            None => {
+                let batch = self.next_batch.get();
+                self.next_batch.set(batch + 1);
+                self.open.set(Some((batch, 0)));
+                Role::Lead(batch)
+            }
+        }
+    }
+
+    /// Stop accepting flushes in a batch, as its sync is about to be submitted.
+    pub fn close(&self, batch: u64) {
+        if let Some((open, joined)) = self.open.get() {
+            if open == batch {
+                self.open.set(None);
+                if joined > 0 {
+                    // Joined flushes wait for this entry, a failed sync must not be lost.
+                    self.results.borrow_mut().insert(batch, (EPENDING, joined));
+                }
+            }
+        }
+    }
+
+    /// Record the result of the sync of a batch.
+    pub fn finish(&self, batch: u64, res: i32) {
+        if let Some((result, _)) = self.results.borrow_mut().get_mut(&batch) {
+            *result = res;
+        }
+    }
+
+    /// Result of the batch a flush joined, if its sync finished.
+    pub fn result(&self, batch: u64) -> Option<i32> {
+        let mut results = self.results.borrow_mut();
+        let (res, waiting) = results.get_mut(&batch)?;
+        if *res == EPENDING {
+            return None;
+        }
+        let res = *res;
+        *waiting -= 1;
+        if *waiting == 0 {
+            results.remove(&batch);
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_open_batch() {
+        let coalescer = FlushCoalescer::new(Duration::from_millis(1));
+        assert_eq!(coalescer.join(), Role::Lead(0));
+        assert_eq!(coalescer.join(), Role::Join(0));
+        assert_eq!(coalescer.join(), Role::Join(0));
+        coalescer.close(0);
+        // Flushes arriving after the batch closed need a new sync.
+        assert_eq!(coalescer.join(), Role::Lead(1));
+
+        assert_eq!(coalescer.result(0), None);
+        coalescer.finish(0, -5);
+        assert_eq!(coalescer.result(0), Some(-5));
+        assert_eq!(coalescer.result(0), Some(-5));
+        // Every joined flush picked up the result.
+        assert_eq!(coalescer.result(0), None);
+    }
+
+    #[test]
+    fn batch_without_joins() {
+        let coalescer = FlushCoalescer::new(Duration::from_millis(1));
+        assert_eq!(coalescer.join(), Role::Lead(0));
+        coalescer.close(0);
+        coalescer.finish(0, 0);
+        assert_eq!(coalescer.result(0), None);
+        assert_eq!(coalescer.join(), Role::Lead(1));
+    }
+
+    #[test]
+    fn close_other_batch() {
+        let coalescer = FlushCoalescer::new(Duration::from_millis(1));
+        assert_eq!(coalescer.join(), Role::Lead(0));
+        coalescer.close(0);
+        assert_eq!(coalescer.join(), Role::Lead(1));
+        // Closing a batch which is not open leaves the open one alone.
+        coalescer.close(0);
+        assert_eq!(coalescer.join(), Role::Join(1));
+    }
+}
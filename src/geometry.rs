@@ -0,0 +1,281 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    layout,
+    target::{self, Target},
+    thin,
+};
+
+/// How multiple targets are combined in a device. Without one, the targets are concatenated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Raid {
+    /// Stripe the device over the targets, with the given stripe size.
+    Striped(u64),
+    /// Store a copy of the device on every target. Targets in the list are write mostly.
+    Mirrored(Vec<PathBuf>),
+}
+
+/// A range of a single target used as the device, like a loop device with an offset and size
+/// limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slice {
+    /// Start of the range on the target.
+    pub offset: u64,
+    /// Size of the range, the rest of the target if not set.
+    pub size_limit: Option<u64>,
+}
+
+/// How the address space of a device is spread over its targets. For thin provisioned devices,
+/// this is the address space of the physical chunks, not of the virtual device.
+#[derive(Debug, Clone)]
+pub enum Geometry {
+    /// A single target, which is used as is. A regular file grows as needed.
+    Single,
+    /// A range of a single target.
+    Slice {
+        /// Start of the range on the target.
+        offset: u64,
+        /// Size of the range.
+        size: u64,
+    },
+    /// Targets concatenated one after the other, holding the size of every target.
+    Linear(Vec<u64>),
+    /// Stripes distributed round robin over targets of equal size.
+    Striped {
+        /// Size of a stripe, a power of 2.
+        stripe_size: u64,
+        /// Amount of targets.
+        nr_targets: usize,
+        /// Size used on every target, a multiple of the stripe size.
+        target_size: u64,
+    },
+    /// Every target holds a copy of the data. Which of them are used for an IO is decided by the
+    /// caller.
+    Mirrored {
+        /// Amount of targets.
+        nr_targets: usize,
+        /// Size used on every target.
+        target_size: u64,
+    },
+}
+
+impl Geometry {
+    /// Build the geometry of a device from its opened targets, and the paths they were opened
+    /// from.
+    pub fn new(targets: &[Target], paths: &[PathBuf], raid: &Option<Raid>) -> io::Result<Geometry> {
+        if targets.len() <= 1 && raid.is_none() {
+            return Ok(Geometry::Single);
+        }
+
+        // Targets are used in whole chunks or stripes, so IO split at those boundaries never
+        // crosses a target.
+        let unit = match raid {
+            Some(Raid::Striped(stripe_size)) => *stripe_size,
+            _ => thin::CHUNK_SIZE,
+        };
+        let mut sizes = Vec::with_capacity(targets.len());
+        for (target, path) in targets.iter().zip(paths) {
+            let size = layout::Layout::new(&target.file)
+                .map_err(io::Error::other)?
+                .size;
+            let size = size - size % unit;
+            if size == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is smaller than {unit} bytes", path.display()),
+                ));
+            }
+            sizes.push(size);
+        }
+        // Striped and mirrored targets all hold the same amount of data, so the smallest one
+        // determines the size used on all of them.
+        let target_size = sizes.iter().copied().min().unwrap();
+        Ok(match raid {
+            Some(Raid::Striped(stripe_size)) => Geometry::Striped {
+                stripe_size: *stripe_size,
+                nr_targets: sizes.len(),
+                target_size,
+            },
+            Some(Raid::Mirrored(_)) => Geometry::Mirrored {
+                nr_targets: sizes.len(),
+                target_size,
+            },
+            None => Geometry::Linear(sizes),
+        })
+    }
+
+    /// Build the geometry of a device using a slice of a single target.
+    pub fn slice(target: &Target, path: &Path, slice: Slice) -> io::Result<Geometry> {
+        // The offset keeps IO on the target aligned for direct IO.
+        if !slice.offset.is_multiple_of(target::BLOCK_SIZE as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset must be a multiple of {}", target::BLOCK_SIZE),
+            ));
+        }
+        let target_size = layout::Layout::new(&target.file)
+            .map_err(io::Error::other)?
+            .size;
+        let available = target_size.saturating_sub(slice.offset);
+        let size = slice.size_limit.unwrap_or(available);
+        if size == 0 || size > available {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{size} bytes at offset {} don't fit in the {target_size} bytes of {}",
+                    slice.offset,
+                    path.display()
+                ),
+            ));
+        }
+        Ok(Geometry::Slice {
+            offset: slice.offset,
+            size,
+        })
+    }
+
+    /// Size of the address space, None if it is not fixed.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Geometry::Single => None,
+            Geometry::Slice { size, .. } => Some(*size),
+            Geometry::Linear(sizes) => Some(sizes.iter().sum()),
+            Geometry::Striped {
+                nr_targets,
+                target_size,
+                ..
+            } => Some(*nr_targets as u64 * target_size),
+            Geometry::Mirrored { target_size, .. } => Some(*target_size),
+        }
+    }
+
+    /// Size in which IO on the device is split, so every piece maps to a contiguous range on a
+    /// single target. Thin provisioned devices are also split at chunk boundaries.
+    pub fn split_size(&self, thin: bool) -> u64 {
+        match (thin, self) {
+            (false, Geometry::Single | Geometry::Slice { .. }) => u64::MAX,
+            (_, Geometry::Striped { stripe_size, .. }) => *stripe_size,
+            _ => thin::CHUNK_SIZE,
+        }
+    }
+
+    /// Amount of targets.
+    pub fn nr_targets(&self) -> usize {
+        match self {
+            Geometry::Single | Geometry::Slice { .. } => 1,
+            Geometry::Linear(sizes) => sizes.len(),
+            Geometry::Striped { nr_targets, .. } | Geometry::Mirrored { nr_targets, .. } => {
+                *nr_targets
+            }
+        }
+    }
+
+    /// Find the target holding an offset, and the offset on that target. The caller must make
+    /// sure IO does not cross a target or stripe boundary. For a mirrored device, this is the
+    /// first target.
+    pub fn route(&self, off: u64) -> (usize, u64) {
+        match self {
+            Geometry::Single | Geometry::Mirrored { .. } => (0, off),
+            Geometry::Slice { offset, .. } => (0, offset + off),
+            Geometry::Linear(sizes) => {
+                let mut start = 0;
+                for (target, size) in sizes.iter().enumerate() {
+                    if off < start + size {
+                        return (target, off - start);
+                    }
+                    start += size;
+                }
+                // Beyond the end, this is rejected by the target.
+                (sizes.len() - 1, off - (start - sizes[sizes.len() - 1]))
+            }
+            Geometry::Striped {
+                stripe_size,
+                nr_targets,
+                ..
+            } => {
+                let stripe = off / stripe_size;
+                let target = (stripe % *nr_targets as u64) as usize;
+                let target_off = stripe / *nr_targets as u64 * stripe_size + off % stripe_size;
+                (target, target_off)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    #[test]
+    fn route_linear() {
+        let geometry = Geometry::Linear(vec![2 * MIB, 3 * MIB]);
+        assert_eq!(geometry.size(), Some(5 * MIB));
+        assert_eq!(geometry.nr_targets(), 2);
+        assert_eq!(geometry.route(0), (0, 0));
+        assert_eq!(geometry.route(2 * MIB - 1), (0, 2 * MIB - 1));
+        assert_eq!(geometry.route(2 * MIB), (1, 0));
+        assert_eq!(geometry.route(5 * MIB - 1), (1, 3 * MIB - 1));
+        // Beyond the end, the offset is past the end of the last target.
+        assert_eq!(geometry.route(5 * MIB), (1, 3 * MIB));
+    }
+
+    #[test]
+    fn route_single() {
+        assert_eq!(Geometry::Single.route(12345), (0, 12345));
+        assert_eq!(Geometry::Single.size(), None);
+        assert_eq!(Geometry::Single.nr_targets(), 1);
+        assert_eq!(Geometry::Single.split_size(false), u64::MAX);
+        assert_eq!(Geometry::Single.split_size(true), thin::CHUNK_SIZE);
+    }
+
+    #[test]
+    fn route_striped() {
+        let stripe_size = 64 << 10;
+        let geometry = Geometry::Striped {
+            stripe_size,
+            nr_targets: 3,
+            target_size: MIB,
+        };
+        assert_eq!(geometry.size(), Some(3 * MIB));
+        assert_eq!(geometry.nr_targets(), 3);
+        assert_eq!(geometry.split_size(false), stripe_size);
+        assert_eq!(geometry.route(0), (0, 0));
+        assert_eq!(geometry.route(stripe_size - 1), (0, stripe_size - 1));
+        assert_eq!(geometry.route(stripe_size), (1, 0));
+        assert_eq!(geometry.route(2 * stripe_size + 5), (2, 5));
+        assert_eq!(geometry.route(3 * stripe_size), (0, stripe_size));
+        assert_eq!(
+            geometry.route(7 * stripe_size + 1),
+            (1, 2 * stripe_size + 1)
+        );
+    }
+
+    #[test]
+    fn route_mirrored() {
+        let geometry = Geometry::Mirrored {
+            nr_targets: 2,
+            target_size: MIB,
+        };
+        assert_eq!(geometry.route(MIB - 1), (0, MIB - 1));
+        assert_eq!(geometry.size(), Some(MIB));
+        assert_eq!(geometry.nr_targets(), 2);
+    }
+
+    #[test]
+    fn route_slice() {
+        let slice = Geometry::Slice {
+            offset: 4096,
+            size: MIB,
+        };
+        assert_eq!(slice.route(0), (0, 4096));
+        assert_eq!(slice.route(10), (0, 4106));
+        assert_eq!(slice.size(), Some(MIB));
+        assert_eq!(slice.nr_targets(), 1);
+        assert_eq!(slice.split_size(false), u64::MAX);
+    }
+}
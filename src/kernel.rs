@@ -1,4 +1,8 @@
-use nix::{ioctl_none, ioctl_read, ioctl_read_bad, request_code_none};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+use nix::{
+    errno::Errno, ioctl_none, ioctl_read, ioctl_read_bad, ioctl_write_ptr_bad, request_code_none,
+};
 
 /// Identifier for ioctl on block devices, defined in linux/fs.h
 const BLK_IOCTL_ID: u8 = 0x12;
@@ -7,12 +11,71 @@ const BLK_IOCTL_ID: u8 = 0x12;
 const BLK_SSZGET_IOCTL_SEQNO: u8 = 104;
 /// Ioctl sequence number for BLKGETSIZE64, defined in linux/fs.h
 const BLK_GETSIZE64_IOCTL_SEQNO: u8 = 114;
+/// Ioctl sequence number for BLKDISCARD, defined in linux/fs.h
+const BLK_DISCARD_IOCTL_SEQNO: u8 = 119;
 /// Ioctl sequence number for BLKIOMIN, defined in linux/fs.h
 const BLK_IOMIN_IOCTL_SEQNO: u8 = 120;
 /// Ioctl sequence number for BLKIOOPT, defined in linux/fs.h
 const BLK_IOOPT_IOCTL_SEQNO: u8 = 121;
 /// Ioctl sequence number for BLKSPBZGET, defined in linux/fs.h
 const BLK_PBSZGET_IOCTL_SEQNO: u8 = 123;
+/// Ioctl sequence number for BLKSECDISCARD, defined in linux/fs.h
+const BLK_SECDISCARD_IOCTL_SEQNO: u8 = 125;
+/// Ioctl sequence number for BLKZEROOUT, defined in linux/fs.h
+const BLK_ZEROOUT_IOCTL_SEQNO: u8 = 127;
+/// Ioctl sequence number for BLKGETZONESZ, defined in linux/blkzoned.h
+const BLK_GETZONESZ_IOCTL_SEQNO: u8 = 132;
+
+/// Fcntl command setting the write lifetime hint of an inode, F_LINUX_SPECIFIC_BASE + 12,
+/// defined in linux/fcntl.h
+const F_SET_RW_HINT: i32 = 1024 + 12;
+
+/// Expected lifetime of data written to a file, defined in linux/fcntl.h. Devices which support
+/// streams can group data with the same lifetime, reducing write amplification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum WriteLifeHint {
+    None = 1,
+    Short = 2,
+    Medium = 3,
+    Long = 4,
+    Extreme = 5,
+}
+
+/// Set the write lifetime hint of the inode of fd. The hint applies to all writes to the inode,
+/// through any file descriptor.
+pub fn set_write_life_hint(fd: BorrowedFd<'_>, hint: WriteLifeHint) -> nix::Result<()> {
+    let hint = hint as u64;
+    // SAFETY: F_SET_RW_HINT only reads a u64 from the pointer, which is valid for the call.
+    let res = unsafe { nix::libc::fcntl(fd.as_raw_fd(), F_SET_RW_HINT, &hint as *const u64) };
+    Errno::result(res).map(drop)
+}
+
+/// Create an eventfd with a counter starting at 0.
+pub fn eventfd() -> nix::Result<OwnedFd> {
+    // SAFETY: eventfd takes no pointers.
+    let fd = Errno::result(unsafe { nix::libc::eventfd(0, nix::libc::EFD_CLOEXEC) })?;
+    // SAFETY: fd was just created and is not owned by anything else.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Restrict the calling thread to run on the given CPUs.
+pub fn set_cpu_affinity(cpus: &[u32]) -> nix::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, for which all zeroes is the empty set.
+    let mut set: nix::libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        if *cpu as usize >= nix::libc::CPU_SETSIZE as usize {
+            return Err(Errno::EINVAL);
+        }
+        // SAFETY: cpu is within the set, as checked above.
+        unsafe { nix::libc::CPU_SET(*cpu as usize, &mut set) };
+    }
+    // SAFETY: set is a valid cpu_set_t of the passed size, pid 0 is the calling thread.
+    let res = unsafe {
+        nix::libc::sched_setaffinity(0, std::mem::size_of::<nix::libc::cpu_set_t>(), &set)
+    };
+    Errno::result(res).map(drop)
+}
 
 // TODO: figure out why these don't work with ioctl_none! but do with ioctl_read_bad! and passing
 // request_code_none!
@@ -52,3 +115,33 @@ ioctl_read_bad! {
     request_code_none!(BLK_IOCTL_ID, BLK_PBSZGET_IOCTL_SEQNO),
     i32
 }
+
+ioctl_write_ptr_bad! {
+    /// Discard a byte range of a block device. The range is passed as [offset, length].
+    ioctl_blkdiscard,
+    request_code_none!(BLK_IOCTL_ID, BLK_DISCARD_IOCTL_SEQNO),
+    [u64; 2]
+}
+
+ioctl_write_ptr_bad! {
+    /// Securely discard a byte range of a block device, so the data can't be recovered from the
+    /// device. The range is passed as [offset, length].
+    ioctl_blksecdiscard,
+    request_code_none!(BLK_IOCTL_ID, BLK_SECDISCARD_IOCTL_SEQNO),
+    [u64; 2]
+}
+
+ioctl_write_ptr_bad! {
+    /// Zero a byte range of a block device. The range is passed as [offset, length].
+    ioctl_blkzeroout,
+    request_code_none!(BLK_IOCTL_ID, BLK_ZEROOUT_IOCTL_SEQNO),
+    [u64; 2]
+}
+
+ioctl_read! {
+    /// Get the zone size of a zoned block device in 512 byte sectors, 0 if it is not zoned.
+    ioctl_blkgetzonesz,
+    BLK_IOCTL_ID,
+    BLK_GETZONESZ_IOCTL_SEQNO,
+    u32
+}
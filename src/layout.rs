@@ -25,6 +25,8 @@ pub struct Layout {
     pub minimum_io_size: u64,
     /// The optimal size of an IO. This is usually not reported and set to 0.
     pub optimal_io_size: u64,
+    /// Size of a zone in bytes if the device is zoned, 0 otherwise.
+    pub zone_size: u64,
 }
 
 /// An error encountered when loading the [`Layout`] of a device.
@@ -50,6 +52,7 @@ impl Layout {
             let mut logical_block_size = 0;
             let mut minimum_io_size = 0;
             let mut optimal_io_size = 0;
+            let mut zone_sectors: u32 = 0;
 
             // SAFETY: ioctls on a valid file descriptor
             unsafe {
@@ -58,6 +61,13 @@ impl Layout {
                 kernel::ioctl_blksszget(fd, &mut logical_block_size as _)?;
                 kernel::ioctl_blkiomin(fd, &mut minimum_io_size as _)?;
                 kernel::ioctl_blkioopt(fd, &mut optimal_io_size as _)?;
+                // Kernels without zoned device support don't know the ioctl, their devices are
+                // never zoned.
+                if let Err(e) = kernel::ioctl_blkgetzonesz(fd, &mut zone_sectors) {
+                    if e != nix::errno::Errno::ENOTTY {
+                        return Err(e.into());
+                    }
+                }
             }
 
             Ok(Layout {
@@ -66,6 +76,7 @@ impl Layout {
                 physical_block_size: physical_block_size as _,
                 minimum_io_size: minimum_io_size as _,
                 optimal_io_size: optimal_io_size as _,
+                zone_size: (zone_sectors as u64) << 9,
             })
         } else if meta.file_type().is_file() {
             // Fallback to reading some info from file metadata.
@@ -76,6 +87,7 @@ impl Layout {
                 // TODO: is this sufficient? or can this be queried?
                 minimum_io_size: 512,
                 optimal_io_size: 0,
+                zone_size: 0,
             })
         } else {
             Err(LayoutError::UnsupportedDeviceType)
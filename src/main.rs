@@ -1,17 +1,22 @@
 use std::{
+    cell::RefCell,
     collections::HashMap,
-    fs::OpenOptions,
-    io,
-    os::{fd::AsRawFd, unix::prelude::OpenOptionsExt},
-    path::PathBuf,
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    ops::Range,
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::prelude::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
     rc::Rc,
     sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
 };
 
-use aes::{
-    cipher::{generic_array::GenericArray, KeyInit},
-    Aes128,
-};
 use clap::{Arg, ArgAction, Command};
 use io_uring::{opcode, squeue, types};
 use libublk::{
@@ -20,28 +25,112 @@ use libublk::{
     exe::{Executor, UringOpFuture},
     io::{UblkDev, UblkIOCtx, UblkQueue},
     sys::{
-        ublk_param_basic, ublk_params, UBLK_IO_COMMIT_AND_FETCH_REQ, UBLK_IO_FETCH_REQ,
-        UBLK_IO_RES_ABORT, UBLK_PARAM_TYPE_BASIC,
+        ublk_param_basic, ublk_param_discard, ublk_params, UBLK_ATTR_READ_ONLY,
+        UBLK_ATTR_VOLATILE_CACHE, UBLK_IO_COMMIT_AND_FETCH_REQ, UBLK_IO_FETCH_REQ,
+        UBLK_IO_RES_ABORT, UBLK_PARAM_TYPE_BASIC, UBLK_PARAM_TYPE_DISCARD,
     },
     UblkSession, UblkSessionBuilder,
 };
-use xts_mode::{get_tweak_default, Xts128};
+use nix::sys::mman::{mprotect, ProtFlags};
 
+mod aqm;
+mod cmp;
+mod crypto;
+mod discard;
+mod error_map;
+mod flatten;
+mod flush;
+mod geometry;
 mod kernel;
 mod layout;
+mod map;
+mod mirror;
+mod overlay;
+mod privileges;
+mod ramdisk;
+mod rmw;
+mod sqe;
+mod sysfs;
+mod target;
+mod thin;
+mod topology;
+mod trace;
+mod wipe;
 
 /// -libc::EINVAL error code
 const EINVAL: i32 = -22;
 /// -libc::EAGAIN error code
 const EAGAIN: i32 = -11;
+/// -libc::EIO error code
+const EIO: i32 = -5;
+/// -libc::EROFS error code
+const EROFS: i32 = -30;
+/// -libc::ENODATA error code
+const ENODATA: i32 = -61;
+
+/// Maximum size of a single discard operation submitted to the target.
+const DISCARD_CHUNK_BYTES: u64 = 1 << 30;
+/// Amount of zeroes written at once for a discard which zeroes the range.
+const ZERO_CHUNK_BYTES: u64 = 1 << 20;
+
+/// Time to wait before checking again if an IO can be submitted, if the depth controller
+/// throttles the target.
+const DEPTH_WAIT_NSEC: u32 = 50_000;
+
+/// Time to wait before checking again if target blocks locked by another write are unlocked.
+const BLOCK_LOCK_WAIT_NSEC: u32 = 10_000;
+/// Time to wait before checking again if a repair of a mirror range, which a write overlaps, is
+/// done.
+const REPAIR_WAIT: Duration = Duration::from_micros(100);
+/// Time to wait before checking again if a chunk of a thin device set up by another write is
+/// ready.
+const CHUNK_WAIT: Duration = Duration::from_micros(100);
+
+/// Maximum amount of file descriptors of the target registered with the queue rings. libublk
+/// supports 32 fixed files, and the first one is the ublk character device.
+const MAX_TARGET_FDS: usize = 31;
+
+/// Amount of hardware queues of a new device if it can't be derived from the topology.
+const DEFAULT_QUEUES: u32 = 1;
+/// Size of the IO buffer of a tag, this is the maximum size of a single request.
+// TODO: figure out good value here
+const IO_BUF_BYTES: u32 = 1 << 19;
+
+/// First bit of the user data holding the target of an IO, above the bits used by libublk. IO
+/// of a request to multiple targets at the same time (e.g. the legs of a mirror) gets different
+/// user data per target this way.
+const TARGET_DATA_SHIFT: u32 = 40;
 
-/// libc::O_DIRECT flag
-const O_DIRECT: i32 = 0x4000;
+/// Byte IO buffers are filled with in debug poison mode.
+const POISON_BYTE: u8 = 0xa5;
+/// Bytes at the end of the IO buffer of every tag which are made inaccessible in debug poison
+/// mode, so an overrun of the buffer faults before reaching the buffer of the next tag.
+const GUARD_BYTES: u32 = 4096;
+
+/// Stripe size of a striped device if none is given.
+const DEFAULT_STRIPE_SIZE: u64 = 64 << 10;
+
+/// Target name selecting a device which stores nothing, to benchmark vblock itself.
+const NULL_TARGET: &str = "null";
+
+/// Size of a new device if none is given.
+const DEFAULT_DEV_SIZE: u64 = 10 << 30;
+/// Queue depth of a new device.
+const DEFAULT_DEPTH: u32 = 1024;
 
 pub fn main() {
+    // Warnings, e.g. about fallbacks, must be visible without configuring the log level.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     // TODO: There are way better ways to do this.
     let matches = Command::new("vblock")
-        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("privileged-check-only")
+                .long("privileged-check-only")
+                .help("only verify that devices can be created in this environment, then exit")
+                .action(ArgAction::SetTrue),
+        )
         .subcommand(
             Command::new("add")
                 .about("Add a new virtual block device")
@@ -59,16 +148,242 @@ pub fn main() {
                     Arg::new("queues")
                         .short('q')
                         .long("queues")
-                        .default_value("1")
-                        .help("number of hardware queues")
+                        .help("number of hardware queues, defaults to one per NUMA node, limited to the hardware queues of the target")
                         .action(ArgAction::Set),
                 )
                 .arg(
                     Arg::new("target")
                         .short('t')
                         .long("target")
-                        .help("backing device")
+                        .required_unless_present("overlay")
+                        .help("backing device, if given multiple times the targets are concatenated. \"mem\" keeps the data in memory instead, without encrypting it, \"null\" reads zeroes and drops all writes")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("scheduler")
+                        .long("scheduler")
+                        .help("io scheduler of the virtual device, e.g. none")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("nr-requests")
+                        .long("nr-requests")
+                        .help("maximum amount of requests allocated for the virtual device")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("rq-affinity")
+                        .long("rq-affinity")
+                        .help("completion cpu affinity of the virtual device (0, 1 or 2)")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("wbt-lat-usec")
+                        .long("wbt-lat-usec")
+                        .help("writeback throttling target latency of the virtual device, 0 disables it")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("discard")
+                        .long("discard")
+                        .help("how discards are handled, discards are not supported if not set")
+                        .value_parser(["ignore", "punch-hole", "passdown", "zero"])
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("discard-granularity")
+                        .long("discard-granularity")
+                        .default_value("4096")
+                        .help("granularity of discards in bytes. Filesystems align discards to it, but the kernel still passes smaller ones on, which are handled like any other discard")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("discard-batch-usec")
+                        .long("discard-batch-usec")
+                        .requires("discard")
+                        .help("merge discards smaller than 1 MiB arriving on a queue within this window, so adjacent ones reach the target as a single discard. Without it, every discard is handled as it arrives")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("audit")
+                        .long("audit")
+                        .help("never modify the backing device, writes are kept in memory and lost on removal")
+                        .conflicts_with("discard")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("rescue")
+                        .long("rescue")
+                        .help("expose a damaged device read only, parts which can't be mapped fail with an IO error instead of failing the whole device")
+                        .conflicts_with_all(["audit", "discard"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .help("size of the device in bytes, defaults to 10 GiB. The size of a thin device is stored on the target, so this is only required when it is created")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("thin")
+                        .long("thin")
+                        .help("only allocate space on the target when it is first written, the target can be smaller than the device")
+                        .conflicts_with("discard")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("base")
+                        .long("base")
+                        .help("read only base image. Chunks which were never written read from it, it is never modified. Implies --thin, the device has the size of the base image")
+                        .conflicts_with_all(["size", "discard"])
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("overlay")
+                        .long("overlay")
+                        .requires("base")
+                        .conflicts_with("target")
+                        .help("file holding all changes made to the base image, this is the target of the device")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("raid0")
+                        .long("raid0")
+                        .help("stripe the device over the targets instead of concatenating them")
+                        .conflicts_with_all(["thin", "base"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("raid1")
+                        .long("raid1")
+                        .help("mirror the device on all targets instead of concatenating them")
+                        .conflicts_with_all(["thin", "raid0", "base"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("offset")
+                        .long("offset")
+                        .help("start of the device on the target in bytes, a multiple of 4096. Only a single target can be used this way")
+                        .conflicts_with_all(["thin", "base", "raid0", "raid1"])
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("sizelimit")
+                        .long("sizelimit")
+                        .help("amount of bytes of the target used for the device, starting at the offset. Defaults to the rest of the target")
+                        .conflicts_with_all(["thin", "base", "raid0", "raid1"])
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("write-mostly")
+                        .long("write-mostly")
+                        .requires("raid1")
+                        .help("only read from this target if no other target of the mirror is left, can be repeated")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("resync")
+                        .long("resync")
+                        .requires("raid1")
+                        .help("copy the data of a healthy target of the mirror to the targets which failed before, so they are used again. Targets which failed are not used otherwise")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("stripe-size")
+                        .long("stripe-size")
+                        .requires("raid0")
+                        .help("size of a stripe in bytes, a power of 2 of at least 4096, defaults to 64 KiB")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("logical-block-size")
+                        .long("logical-block-size")
+                        .default_value("512")
+                        .value_parser(["512", "1024", "2048", "4096"])
+                        .help("logical block size of the device in bytes. If the target has larger blocks, writes of smaller blocks are emulated with a read-modify-write")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("target-latency-usec")
+                        .long("target-latency-usec")
+                        .help("adapt the amount of IOs in flight to the backing device to keep its latency below this value")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("write-cache")
+                        .long("write-cache")
+                        .default_value("auto")
+                        .value_parser(["auto", "on", "off"])
+                        .help("advertise a volatile write cache, so the kernel sends flushes. auto enables it unless all targets are block devices without a write cache, used with direct IO")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("flush-coalesce-usec")
+                        .long("flush-coalesce-usec")
+                        .help("complete flushes arriving on a queue within this window with a single sync of the targets. Without it, every flush syncs the targets")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("crypto")
+                        .long("crypto")
+                        .default_value("software")
+                        .value_parser(["software", "kernel"])
+                        .help("encryption implementation, kernel uses the kernel crypto api")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("fail-fast")
+                        .long("fail-fast")
+                        .help("fail reads of extents which returned an unrecoverable error before, instead of retrying them on the target")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("stale-device")
+                        .long("stale-device")
+                        .default_value("fail")
+                        .value_parser(["fail", "clean"])
+                        .help("what to do if a device with the requested id is left behind by a vblock process which is gone. clean removes it and creates the device again with the same id")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("write-life")
+                        .long("write-life")
+                        .value_parser(["none", "short", "medium", "long", "extreme"])
+                        .help("expected lifetime of the data written to the targets, passed to the targets as a write hint so devices supporting streams can group data with the same lifetime")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("key-file")
+                        .long("key-file")
+                        .help("file holding the 64 byte AES-256-XTS key of the device")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("key-env")
+                        .long("key-env")
+                        .conflicts_with("key-file")
+                        .help("environment variable holding the key of the device as 128 hex digits, instead of a key file")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("key-name")
+                        .long("key-name")
+                        .conflicts_with_all(["key-file", "key-env"])
+                        .help("name of the key of the device in /etc/vblock/keys, which holds a raw 64 byte key per file only accessible by its owner. Only the name is passed, the key is never stored with the device. Keys can't be rotated, as the data of the device would have to be encrypted again")
                         .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("insecure-test-key")
+                        .long("insecure-test-key")
+                        .conflicts_with_all(["key-file", "key-env", "key-name"])
+                        .help("encrypt data with a public test key instead of a key file or variable, so it is not protected. Only meant for testing")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("debug-poison")
+                        .long("debug-poison")
+                        .help("fill the IO buffer of a request with a poison pattern before a read, and after any other request completes, so parts of a read not filled by the target, or data of an earlier request leaking into a write, stand out. The last page of every IO buffer is made inaccessible as a guard page, so an overrun into the buffer of the next request faults")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -80,12 +395,135 @@ pub fn main() {
                         .required(true)
                         .help("device id to delete")
                         .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("wipe")
+                        .long("wipe")
+                        .help("destroy all data on the targets of the device after removing it. With an offset or size limit, only the range used by the device is wiped")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(Command::new("list").about("List all virtual block devices"))
+        .subcommand(
+            Command::new("reconcile")
+                .about("Find devices whose vblock process is gone, and error maps of devices which no longer exist")
+                .arg(
+                    Arg::new("clean")
+                        .long("clean")
+                        .help("remove the devices and error maps which are found")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Show information about a virtual block device")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .required(true)
+                        .help("device id to show")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("errors")
+                        .long("errors")
+                        .help("list the extents which returned an unrecoverable error, in bytes")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("cmp")
+                .about("Compare a virtual block device with an image or another device. Holes in sparse images and chunks of thin devices which were never written are skipped, other block devices are read in full")
+                .arg(
+                    Arg::new("a")
+                        .required(true)
+                        .help("first device or image")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("b")
+                        .required(true)
+                        .help("second device or image")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("block-size")
+                        .long("block-size")
+                        .default_value("4096")
+                        .value_parser(parse_block_size)
+                        .help("granularity of reported differences in bytes, a multiple of 512")
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("flatten")
+                .about("Export the contents of a virtual block device to a sparse raw image")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .required(true)
+                        .help("device id to export")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .required(true)
+                        .help("path of the raw image to create, must not exist yet")
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("map")
+                .about("Show where a range of a virtual block device is stored on its targets. Data on the targets is encrypted")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .required(true)
+                        .help("device id to map")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("offset")
+                        .long("offset")
+                        .required(true)
+                        .help("start of the range in bytes")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("len")
+                        .long("len")
+                        .required(true)
+                        .help("length of the range in bytes")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .default_value("human")
+                        .value_parser(["human", "json"])
+                        .help("output format, json lists the status of every extent like qemu-img map, so holes can be skipped")
+                        .action(ArgAction::Set),
+                ),
+        )
         .subcommand(Command::new("features").about("List all supported features"))
         .get_matches();
 
+    if matches.get_flag("privileged-check-only") {
+        for e in privileges::warnings(DEFAULT_QUEUES, DEFAULT_DEPTH) {
+            eprintln!("warning: {e}");
+        }
+        let errors = privileges::check();
+        for e in &errors {
+            eprintln!("{e}");
+        }
+        if !errors.is_empty() {
+            std::process::exit(1);
+        }
+        println!("all privilege checks passed");
+        return;
+    }
+
     match matches.subcommand() {
         Some(("add", add_matches)) => {
             let id = add_matches
@@ -93,18 +531,283 @@ pub fn main() {
                 .unwrap()
                 .parse::<i32>()
                 .unwrap_or(-1);
+            let targets: Vec<PathBuf> = match add_matches.get_one::<String>("overlay") {
+                Some(overlay) => vec![overlay.into()],
+                None => add_matches
+                    .get_many::<String>("target")
+                    .unwrap()
+                    .map(PathBuf::from)
+                    .collect(),
+            };
             let nr_queues = add_matches
                 .get_one::<String>("queues")
+                .map(|v| v.parse::<u32>().unwrap_or(DEFAULT_QUEUES))
+                .unwrap_or_else(|| topology::default_queues(&targets[0]));
+            let depth = DEFAULT_DEPTH;
+            let queue_settings = sysfs::QueueSettings {
+                scheduler: add_matches.get_one::<String>("scheduler").cloned(),
+                nr_requests: add_matches
+                    .get_one::<String>("nr-requests")
+                    .map(|v| v.parse::<u32>().unwrap()),
+                rq_affinity: add_matches
+                    .get_one::<String>("rq-affinity")
+                    .map(|v| v.parse::<u32>().unwrap()),
+                wbt_lat_usec: add_matches
+                    .get_one::<String>("wbt-lat-usec")
+                    .map(|v| v.parse::<u64>().unwrap()),
+            };
+            let discard = add_matches
+                .get_one::<String>("discard")
+                .map(|v| v.parse::<discard::DiscardPolicy>().unwrap());
+            let discard_granularity = add_matches
+                .get_one::<String>("discard-granularity")
                 .unwrap()
                 .parse::<u32>()
-                .unwrap_or(1);
-            let target = add_matches.get_one::<String>("target").unwrap();
-            let depth = 1024;
-            add_vblock_device(id, nr_queues, depth, target.into());
+                .unwrap();
+            let audit = add_matches.get_flag("audit");
+            let rescue = add_matches.get_flag("rescue");
+            let size = add_matches
+                .get_one::<String>("size")
+                .map(|v| v.parse::<u64>().unwrap());
+            let base = add_matches.get_one::<String>("base").map(PathBuf::from);
+            let thin = add_matches.get_flag("thin") || base.is_some();
+            let raid = if add_matches.get_flag("raid0") {
+                Some(geometry::Raid::Striped(
+                    add_matches
+                        .get_one::<String>("stripe-size")
+                        .map(|v| v.parse::<u64>().unwrap())
+                        .unwrap_or(DEFAULT_STRIPE_SIZE),
+                ))
+            } else if add_matches.get_flag("raid1") {
+                Some(geometry::Raid::Mirrored(
+                    add_matches
+                        .get_many::<String>("write-mostly")
+                        .unwrap_or_default()
+                        .map(PathBuf::from)
+                        .collect(),
+                ))
+            } else {
+                None
+            };
+            let offset = add_matches
+                .get_one::<String>("offset")
+                .map(|v| v.parse::<u64>().unwrap());
+            let size_limit = add_matches
+                .get_one::<String>("sizelimit")
+                .map(|v| v.parse::<u64>().unwrap());
+            let slice = (offset.is_some() || size_limit.is_some()).then(|| geometry::Slice {
+                offset: offset.unwrap_or(0),
+                size_limit,
+            });
+            let logical_block_size = add_matches
+                .get_one::<String>("logical-block-size")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+            let target_latency = add_matches
+                .get_one::<String>("target-latency-usec")
+                .map(|v| Duration::from_micros(v.parse::<u64>().unwrap()));
+            let write_cache = match add_matches
+                .get_one::<String>("write-cache")
+                .unwrap()
+                .as_str()
+            {
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            };
+            let flush_window = add_matches
+                .get_one::<String>("flush-coalesce-usec")
+                .map(|v| Duration::from_micros(v.parse::<u64>().unwrap()));
+            let discard_window = add_matches
+                .get_one::<String>("discard-batch-usec")
+                .map(|v| Duration::from_micros(v.parse::<u64>().unwrap()));
+            let key_file = add_matches.get_one::<String>("key-file");
+            let key_env = add_matches.get_one::<String>("key-env");
+            let key_name = add_matches.get_one::<String>("key-name");
+            let key = match (key_file, key_env, key_name) {
+                (Some(path), _, _) => match crypto::load_key(Path::new(path)) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        eprintln!("could not load key from {path}: {e}");
+                        std::process::exit(1);
+                    }
+                },
+                (None, Some(name), _) => match crypto::load_key_env(name) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        eprintln!("could not load key from environment variable {name}: {e}");
+                        std::process::exit(1);
+                    }
+                },
+                (None, None, Some(name)) => match crypto::load_named_key(name) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        eprintln!("could not load key {name}: {e}");
+                        std::process::exit(1);
+                    }
+                },
+                (None, None, None) if add_matches.get_flag("insecure-test-key") => {
+                    eprintln!("warning: data is encrypted with a public test key");
+                    crypto::TEST_KEY
+                }
+                (None, None, None) => {
+                    eprintln!("a key is required, pass --key-file, --key-env or --key-name");
+                    std::process::exit(1);
+                }
+            };
+            let fail_fast = add_matches.get_flag("fail-fast");
+            let write_life =
+                add_matches
+                    .get_one::<String>("write-life")
+                    .map(|v| match v.as_str() {
+                        "none" => kernel::WriteLifeHint::None,
+                        "short" => kernel::WriteLifeHint::Short,
+                        "medium" => kernel::WriteLifeHint::Medium,
+                        "long" => kernel::WriteLifeHint::Long,
+                        _ => kernel::WriteLifeHint::Extreme,
+                    });
+            let debug_poison = add_matches.get_flag("debug-poison");
+            let cipher_backend = match add_matches.get_one::<String>("crypto").unwrap().as_str() {
+                "kernel" => crypto::CipherBackend::Kernel,
+                _ => crypto::CipherBackend::Software,
+            };
+
+            // Raise the memlock limit before checking, a failure to do so is reported as a
+            // warning on kernels which need it.
+            let _ = privileges::raise_memlock(nr_queues, depth);
+            for e in privileges::warnings(nr_queues, depth) {
+                eprintln!("warning: {e}");
+            }
+            let errors = privileges::check();
+            if !errors.is_empty() {
+                for e in errors {
+                    eprintln!("{e}");
+                }
+                std::process::exit(1);
+            }
+
+            // A given id may be held by a device of a crashed vblock process.
+            if id >= 0 {
+                let clean = add_matches.get_one::<String>("stale-device").unwrap() == "clean";
+                if let Err(e) = clear_stale_device(id, clean) {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+
+            add_vblock_device(AddOptions {
+                id,
+                depth,
+                queue_settings,
+                discard_granularity,
+                fail_fast,
+                write_life,
+                write_cache,
+                flush_window,
+                discard_window,
+                debug_poison,
+                backing: BackingConfig {
+                    paths: targets,
+                    nr_queues,
+                    discard,
+                    audit,
+                    rescue,
+                    size,
+                    thin,
+                    base,
+                    raid,
+                    slice,
+                    resync: add_matches.get_flag("resync"),
+                    logical_block_size,
+                    target_latency,
+                    cipher_backend,
+                    key,
+                },
+            });
         }
         Some(("list", _)) => UblkSession::for_each_dev_id(|dev_id| {
             UblkCtrl::new_simple(dev_id as i32, 0).unwrap().dump();
         }),
+        Some(("reconcile", reconcile_matches)) => {
+            if let Err(e) = reconcile_devices(reconcile_matches.get_flag("clean")) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        Some(("info", info_matches)) => {
+            let id = info_matches
+                .get_one::<String>("id")
+                .unwrap()
+                .parse::<u32>()
+                .unwrap();
+            if !info_matches.get_flag("errors") {
+                UblkCtrl::new_simple(id as i32, 0).unwrap().dump();
+                return;
+            }
+            match error_map::load(id) {
+                Ok(extents) if extents.is_empty() => println!("no errors"),
+                Ok(extents) => {
+                    for extent in extents {
+                        println!("{}..{}", extent.start, extent.end);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("could not load the error map of device {id}: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("cmp", cmp_matches)) => {
+            let a = cmp_matches.get_one::<String>("a").unwrap();
+            let b = cmp_matches.get_one::<String>("b").unwrap();
+            let block_size = *cmp_matches.get_one::<u64>("block-size").unwrap();
+            match compare_devices(a.into(), b.into(), block_size) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("could not compare {a} and {b}: {e}");
+                    std::process::exit(2);
+                }
+            }
+        }
+        Some(("flatten", flatten_matches)) => {
+            let id = flatten_matches
+                .get_one::<String>("id")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap();
+            let output = flatten_matches.get_one::<String>("output").unwrap();
+            match flatten_device(id, output.into()) {
+                Ok(written) => println!("wrote {written} bytes of data to {output}"),
+                Err(e) => {
+                    eprintln!("could not export device {id} to {output}: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("map", map_matches)) => {
+            let id = map_matches
+                .get_one::<String>("id")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap();
+            let offset = map_matches
+                .get_one::<String>("offset")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+            let len = map_matches
+                .get_one::<String>("len")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+            let json = map_matches.get_one::<String>("output").unwrap() == "json";
+            if let Err(e) = map_device(id, offset, len, json) {
+                eprintln!("could not map device {id}: {e}");
+                std::process::exit(1);
+            }
+        }
         Some(("del", del_matches)) => {
             let id = del_matches
                 .get_one::<String>("id")
@@ -112,10 +815,37 @@ pub fn main() {
                 .parse::<i32>()
                 .unwrap();
             let mut ctrl = UblkCtrl::new_simple(id, 0).unwrap();
+            // The targets are only known while the device exists.
+            let (targets, slice) = if del_matches.get_flag("wipe") {
+                match device_targets(&ctrl) {
+                    Some(targets) => (targets, device_slice(&ctrl)),
+                    None => {
+                        eprintln!("targets of device {id} are unknown, not removing it");
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                (Vec::new(), None)
+            };
             // Stop the device
             let _ = ctrl.kill_dev();
             // And remove it
             let _ = ctrl.del_dev();
+            let _ = std::fs::remove_file(error_map::path(id as u32));
+
+            let mut failed = false;
+            for target in targets {
+                match wipe::wipe(&target, slice) {
+                    Ok(method) => println!("wiped {} by {method}", target.display()),
+                    Err(e) => {
+                        eprintln!("could not wipe {}: {e}", target.display());
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
         }
         Some(("features", _)) => match UblkCtrl::get_features() {
             Some(f) => {
@@ -151,9 +881,127 @@ pub fn main() {
     }
 }
 
+/// Options of a new device, as given on the command line.
+struct AddOptions {
+    /// Id of the device, or -1 to let the kernel pick one.
+    id: i32,
+    depth: u32,
+    queue_settings: sysfs::QueueSettings,
+    discard_granularity: u32,
+    /// See [`Backing::fail_fast`].
+    fail_fast: bool,
+    write_life: Option<kernel::WriteLifeHint>,
+    /// Whether the device has a volatile write cache, None to detect it from the targets.
+    write_cache: Option<bool>,
+    /// See [`Backing::flush_window`].
+    flush_window: Option<Duration>,
+    /// See [`Backing::discard_window`].
+    discard_window: Option<Duration>,
+    /// See [`Backing::poison`].
+    debug_poison: bool,
+    backing: BackingConfig,
+}
+
+/// Targets of a device and how they are used, see [`Backing::new`].
+struct BackingConfig {
+    paths: Vec<PathBuf>,
+    nr_queues: u32,
+    discard: Option<discard::DiscardPolicy>,
+    /// Whether writes go to an in-memory overlay instead of the target.
+    audit: bool,
+    /// Whether a damaged target is opened read only, loading as much of it as possible.
+    rescue: bool,
+    size: Option<u64>,
+    thin: bool,
+    base: Option<PathBuf>,
+    raid: Option<geometry::Raid>,
+    slice: Option<geometry::Slice>,
+    /// Whether failed legs of a mirror are resynced before the device is created.
+    resync: bool,
+    logical_block_size: u64,
+    target_latency: Option<Duration>,
+    cipher_backend: crypto::CipherBackend,
+    key: [u8; crypto::KEY_SIZE],
+}
+
 /// Add a new virtual block device
-fn add_vblock_device(id: i32, nr_queues: u32, depth: u32, target: PathBuf) {
-    let (backing, target) = Backing::new(target).unwrap();
+fn add_vblock_device(opts: AddOptions) {
+    let AddOptions {
+        id,
+        depth,
+        queue_settings,
+        discard_granularity,
+        fail_fast,
+        write_life,
+        write_cache,
+        flush_window,
+        discard_window,
+        debug_poison,
+        backing: config,
+    } = opts;
+    let targets = &config.paths;
+    let nr_queues = config.nr_queues;
+    let discard = config.discard;
+    let raid = &config.raid;
+    // Stored with the device, so the targets can be found when it is removed, from any working
+    // directory. A ramdisk or null device has no targets on disk.
+    let target_paths: Vec<String> = if ramdisk::is_ramdisk(targets) || is_null_target(targets) {
+        Vec::new()
+    } else {
+        targets
+            .iter()
+            .map(|target| {
+                std::fs::canonicalize(target)
+                    .unwrap_or_else(|_| target.clone())
+                    .display()
+                    .to_string()
+            })
+            .collect()
+    };
+    // Stored so the layout of the device can be rebuilt from its targets by other commands.
+    let layout_json = serde_json::json!({
+        "thin": config.thin,
+        "raid0": match raid {
+            Some(geometry::Raid::Striped(stripe_size)) => Some(stripe_size),
+            _ => None,
+        },
+        "raid1": matches!(raid, Some(geometry::Raid::Mirrored(_))),
+        "slice": config.slice.map(|slice| serde_json::json!({
+            "offset": slice.offset,
+            "sizelimit": slice.size_limit,
+        })),
+        "base": config.base.as_ref().map(|base| {
+            std::fs::canonicalize(base)
+                .unwrap_or_else(|_| base.clone())
+                .display()
+                .to_string()
+        }),
+    });
+    let mut backing = Backing::new(config).unwrap();
+    backing.poison = debug_poison;
+    backing.fail_fast = fail_fast;
+    backing.flush_window = flush_window;
+    backing.discard_window = discard_window;
+    // Without a volatile write cache the kernel never sends flushes, so writes must be durable
+    // once they complete. Writes through the page cache or to a device which caches them are
+    // only durable once the targets are synced.
+    let cached = !backing.read_only && backing.targets_cache_writes();
+    let volatile_cache = write_cache.unwrap_or(cached);
+    if cached && !volatile_cache {
+        log::warn!(
+            "targets cache writes, but the device has no write cache, data may be lost on a crash"
+        );
+    }
+    let mut attrs = 0;
+    if backing.read_only {
+        attrs |= UBLK_ATTR_READ_ONLY;
+    }
+    if volatile_cache {
+        attrs |= UBLK_ATTR_VOLATILE_CACHE;
+    }
+    if let Some(hint) = write_life {
+        backing.set_write_life_hint(hint);
+    }
 
     let sess = UblkSessionBuilder::default()
         .name("vblock")
@@ -161,104 +1009,905 @@ fn add_vblock_device(id: i32, nr_queues: u32, depth: u32, target: PathBuf) {
         //.ctrl_flags(libublk::sys::UBLK_F_UNPRIVILEGED_DEV)
         .nr_queues(nr_queues)
         .depth(depth)
-        // TODO: figure out good value here
-        .io_buf_bytes(1u32 << 19)
+        .io_buf_bytes(IO_BUF_BYTES)
         .dev_flags(UBLK_DEV_F_ADD_DEV | UBLK_DEV_F_ASYNC)
         .build()
         .unwrap();
 
     let (mut ctrl, dev) = sess
         .create_devices(|dev| {
-            // Register backing file -> allows uring fixed io
+            // Register backing files -> allows uring fixed io
             let tgt = &mut dev.tgt;
-            let nr_fds = tgt.nr_fds;
-            tgt.fds[nr_fds as usize] = target.as_raw_fd();
-            tgt.nr_fds += 1;
+            for file in backing.files.iter() {
+                let nr_fds = tgt.nr_fds;
+                tgt.fds[nr_fds as usize] = file.as_raw_fd();
+                tgt.nr_fds += 1;
+            }
 
-            dev.tgt.dev_size = 10 << 30;
+            dev.tgt.dev_size = backing.size;
             dev.tgt.params = ublk_params {
                 types: UBLK_PARAM_TYPE_BASIC,
                 basic: ublk_param_basic {
+                    attrs,
                     // TODO: figure out these params
-                    logical_bs_shift: 9,
-                    physical_bs_shift: 9,
+                    logical_bs_shift: backing.logical_block_size.trailing_zeros() as u8,
+                    // With emulation, IO in whole target blocks avoids the read-modify-write.
+                    physical_bs_shift: backing.physical_block_size().trailing_zeros() as u8,
                     // bitshifts of 1 in sector?
                     io_opt_shift: 9,
-                    io_min_shift: 9,
-                    max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                    io_min_shift: backing.physical_block_size().trailing_zeros() as u8,
+                    max_sectors: dev.dev_info.max_io_buf_bytes.min(backing.io_buf_bytes()) >> 9,
                     dev_sectors: dev.tgt.dev_size >> 9,
                     ..Default::default()
                 },
                 ..Default::default()
             };
-            dev.set_target_json(serde_json::json!({"vblock": id}));
+            if discard.is_some() {
+                dev.tgt.params.types |= UBLK_PARAM_TYPE_DISCARD;
+                dev.tgt.params.discard = ublk_param_discard {
+                    discard_granularity,
+                    // Discards don't use the IO buffer, so advertise the largest size possible.
+                    // These are split when handling them.
+                    max_discard_sectors: u32::MAX,
+                    max_discard_segments: 1,
+                    ..Default::default()
+                };
+            }
+            dev.set_target_json(serde_json::json!({
+                "vblock": id,
+                "targets": target_paths,
+                "layout": layout_json,
+            }));
 
             Ok(0)
         })
         .unwrap();
+    backing.errors = Some(Arc::new(error_map::ErrorMap::new(dev.dev_info.dev_id)));
 
-    sess.run_target(&mut ctrl, &dev, backing.as_queue_handler(), |device_id| {
-        let mut device_ctrl = UblkCtrl::new_simple(device_id, 0).unwrap();
-        device_ctrl.dump();
-    })
+    sess.run_target(
+        &mut ctrl,
+        &dev,
+        backing.as_queue_handler(),
+        move |device_id| {
+            // Errors are already logged, and the device works fine with the kernel defaults.
+            let _ = queue_settings.apply(&format!("ublkb{device_id}"));
+
+            let mut device_ctrl = UblkCtrl::new_simple(device_id, 0).unwrap();
+            device_ctrl.dump();
+        },
+    )
     .unwrap();
 }
 
-#[derive(Clone)]
-struct Backing {
-    enc: Arc<Xts128<Aes128>>,
+/// Whether the targets of a device select a null device.
+fn is_null_target(targets: &[PathBuf]) -> bool {
+    matches!(targets, [target] if target.as_os_str() == NULL_TARGET)
 }
 
-impl Backing {
+/// Check if a device with the given id exists without a process serving it, e.g. because the
+/// vblock process serving it crashed. Such a device blocks its id until it is removed, which is
+/// only done if `clean` is set.
+fn clear_stale_device(id: i32, clean: bool) -> Result<(), Box<dyn std::error::Error>> {
+    // Opening the control of a device only succeeds if it exists.
+    let Ok(mut ctrl) = UblkCtrl::new_simple(id, 0) else {
+        return Ok(());
+    };
+    let pid = ctrl.dev_info.ublksrv_pid;
+    if process_exists(pid) {
+        return Err(format!("device {id} is in use by process {pid}").into());
+    }
+    if !clean {
+        return Err(format!(
+            "device {id} exists, but its process is gone. Use --stale-device clean to remove it"
+        )
+        .into());
+    }
+
+    log::warn!("removing device {id}, its process {pid} is gone");
+    let _ = ctrl.kill_dev();
+    ctrl.del_dev()
+        .map_err(|e| format!("could not remove stale device {id}: {e:?}"))?;
+    let _ = std::fs::remove_file(error_map::path(id as u32));
+
+    Ok(())
+}
+
+/// Whether the process serving a device is running.
+fn process_exists(pid: i32) -> bool {
+    pid > 0 && Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Compare the devices in the kernel with the state vblock keeps for them. Orphans are vblock
+/// devices whose process is gone, ghosts are error maps of devices which no longer exist. With
+/// clean, both are removed. Devices of other ublk servers are left alone.
+fn reconcile_devices(clean: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let ids = Rc::new(RefCell::new(Vec::new()));
+    let found = ids.clone();
+    UblkSession::for_each_dev_id(move |dev_id| found.borrow_mut().push(dev_id));
+    let ids = ids.take();
+
+    for &id in &ids {
+        let Ok(ctrl) = UblkCtrl::new_simple(id as i32, 0) else {
+            continue;
+        };
+        let vblock = ctrl
+            .get_target_data_from_json()
+            .is_some_and(|data| data.get("vblock").is_some());
+        if !vblock {
+            continue;
+        }
+        let pid = ctrl.dev_info.ublksrv_pid;
+        if process_exists(pid) {
+            println!("device {id}: running in process {pid}");
+        } else if clean {
+            clear_stale_device(id as i32, true)?;
+            println!("device {id}: orphan, removed");
+        } else {
+            println!("device {id}: orphan, process {pid} is gone");
+        }
+    }
+
+    for id in error_map::ids()? {
+        if ids.contains(&id) {
+            continue;
+        }
+        if clean {
+            std::fs::remove_file(error_map::path(id))?;
+            println!("error map {id}: ghost, removed");
+        } else {
+            println!("error map {id}: ghost, device {id} does not exist");
+        }
+    }
+
+    Ok(())
+}
+
+/// Paths of the targets of an existing device, as stored when it was added.
+fn device_targets(ctrl: &UblkCtrl) -> Option<Vec<PathBuf>> {
+    let data = ctrl.get_target_data_from_json()?;
+    data.get("targets")?
+        .as_array()?
+        .iter()
+        .map(|target| target.as_str().map(PathBuf::from))
+        .collect()
+}
+
+/// Slice of its target a device uses, as stored when the device was added.
+fn device_slice(ctrl: &UblkCtrl) -> Option<geometry::Slice> {
+    let data = ctrl.get_target_data_from_json()?;
+    let slice = data.get("layout")?.get("slice")?.as_object()?;
+    Some(geometry::Slice {
+        offset: slice["offset"].as_u64().unwrap_or(0),
+        size_limit: slice["sizelimit"].as_u64(),
+    })
+}
+
+/// Layout and targets of a device, as stored when it was added.
+fn device_layout(
+    ctrl: &UblkCtrl,
+) -> Result<(serde_json::Value, Vec<PathBuf>), Box<dyn std::error::Error>> {
+    let layout = ctrl
+        .get_target_data_from_json()
+        .and_then(|data| data.get("layout").cloned())
+        .ok_or("layout of the device is unknown")?;
+    let paths = device_targets(ctrl).ok_or("targets of the device are unknown")?;
+    if paths.is_empty() {
+        return Err("device has no targets".into());
+    }
+    Ok((layout, paths))
+}
+
+/// Resolve a range of a device to where it is stored on its targets. The layout is rebuilt from
+/// the targets, as stored when the device was added. Thin provisioning tables are written before
+/// the data is, so the mapping on the target is the one in use by the device.
+fn resolve_device(
+    ctrl: &UblkCtrl,
+    layout: &serde_json::Value,
+    paths: &[PathBuf],
+    offset: u64,
+    len: u64,
+) -> Result<Vec<map::Extent>, Box<dyn std::error::Error>> {
+    let raid = if let Some(stripe_size) = layout["raid0"].as_u64() {
+        Some(geometry::Raid::Striped(stripe_size))
+    } else if layout["raid1"].as_bool() == Some(true) {
+        Some(geometry::Raid::Mirrored(Vec::new()))
+    } else {
+        None
+    };
+
+    let mut targets = Vec::with_capacity(paths.len());
+    for path in paths {
+        targets.push(target::open(path, false)?);
+    }
+    let geometry = match device_slice(ctrl) {
+        Some(slice) => geometry::Geometry::slice(&targets[0], &paths[0], slice)?,
+        None => geometry::Geometry::new(&targets, paths, &raid)?,
+    };
+    let thin = match layout["thin"].as_bool() {
+        Some(true) => Some(thin::ThinMap::open(
+            &targets[0].file,
+            None,
+            None,
+            None,
+            false,
+        )?),
+        _ => None,
+    };
+
+    let base = layout["base"].is_string();
+
+    Ok(map::resolve(&geometry, thin.as_ref(), base, offset, len)?)
+}
+
+/// Print where a range of a device is stored on its targets.
+fn map_device(
+    id: i32,
+    offset: u64,
+    len: u64,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ctrl = UblkCtrl::new_simple(id, 0).map_err(|e| format!("could not open device: {e:?}"))?;
+    let (layout, paths) = device_layout(&ctrl)?;
+    let base = layout["base"].as_str();
+
+    let extents = resolve_device(&ctrl, &layout, &paths, offset, len)?;
+    if json {
+        // Like qemu-img map: depth 0 is the device itself, 1 its base image. Unmapped ranges
+        // read as zeroes without any data behind them.
+        let extents: Vec<serde_json::Value> = extents
+            .iter()
+            .map(|extent| {
+                let (depth, data, locations) = match &extent.location {
+                    map::Location::Unmapped => (0, false, Vec::new()),
+                    map::Location::Base => (
+                        1,
+                        true,
+                        vec![serde_json::json!({
+                            "target": base.unwrap_or_default(),
+                            "offset": extent.start,
+                        })],
+                    ),
+                    map::Location::Targets(locations) => (
+                        0,
+                        true,
+                        locations
+                            .iter()
+                            .map(|(target, off)| {
+                                serde_json::json!({
+                                    "target": paths[*target].display().to_string(),
+                                    "offset": off,
+                                })
+                            })
+                            .collect(),
+                    ),
+                };
+                serde_json::json!({
+                    "start": extent.start,
+                    "length": extent.len,
+                    "depth": depth,
+                    "data": data,
+                    "zero": !data,
+                    "locations": locations,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(extents));
+        return Ok(());
+    }
+
+    for extent in extents {
+        let range = format!("{}..{}", extent.start, extent.start + extent.len);
+        match extent.location {
+            map::Location::Unmapped => println!("{range} unmapped"),
+            map::Location::Base => {
+                println!("{range} {} {}", base.unwrap_or_default(), extent.start)
+            }
+            map::Location::Targets(locations) => {
+                for (target, off) in locations {
+                    println!("{range} {} {off}", paths[target].display());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a block size in bytes, which must be a non zero multiple of 512.
+fn parse_block_size(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(size) if size != 0 && size % 512 == 0 => Ok(size),
+        Ok(_) => Err("must be a non zero multiple of 512".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Compare 2 devices or images, printing the differing ranges. Returns true if they are equal.
+fn compare_devices(
+    a: PathBuf,
+    b: PathBuf,
+    block_size: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let a_file = File::open(&a)?;
+    let b_file = File::open(&b)?;
+    let a_size = layout::Layout::new(&a_file)?.size;
+    let b_size = layout::Layout::new(&b_file)?.size;
+    let a = cmp::Source {
+        file: &a_file,
+        size: a_size,
+        data: device_data(&a, a_size)?,
+    };
+    let b = cmp::Source {
+        file: &b_file,
+        size: b_size,
+        data: device_data(&b, b_size)?,
+    };
+
+    let differences = cmp::compare(&a, &b, block_size)?;
+    for range in &differences {
+        println!(
+            "{:#x}-{:#x} ({} bytes)",
+            range.start,
+            range.end,
+            range.end - range.start
+        );
+    }
+    if a_size != b_size {
+        println!("size differs: {a_size} and {b_size} bytes");
+    }
+
+    Ok(differences.is_empty())
+}
+
+/// Ranges of a thin device which hold data, from its thin provisioning table. Chunks which were
+/// never written read as zeroes. Returns None if `path` is not a thin vblock device, or if
+/// unwritten chunks read from a base image.
+fn device_data(
+    path: &Path,
+    size: u64,
+) -> Result<Option<Vec<Range<u64>>>, Box<dyn std::error::Error>> {
+    let Some(id) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("ublkb"))
+        .and_then(|id| id.parse::<i32>().ok())
+    else {
+        return Ok(None);
+    };
+    let Ok(ctrl) = UblkCtrl::new_simple(id, 0) else {
+        return Ok(None);
+    };
+    let (layout, paths) = device_layout(&ctrl)?;
+    if layout["thin"].as_bool() != Some(true) || layout["base"].is_string() {
+        return Ok(None);
+    }
+
+    let mut data: Vec<Range<u64>> = Vec::new();
+    for extent in resolve_device(&ctrl, &layout, &paths, 0, size)? {
+        if extent.location == map::Location::Unmapped {
+            continue;
+        }
+        let range = extent.start..extent.start + extent.len;
+        match data.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => data.push(range),
+        }
+    }
+    Ok(Some(data))
+}
+
+/// Export the decrypted contents of a running device to a new sparse raw image. Returns the
+/// amount of data bytes written.
+fn flatten_device(id: i32, output: PathBuf) -> Result<u64, Box<dyn std::error::Error>> {
+    let src = File::open(format!("/dev/ublkb{id}"))?;
+    let size = layout::Layout::new(&src)?.size;
+    let dst = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)?;
+
+    Ok(flatten::flatten(&src, size, &dst)?)
+}
+
+#[derive(Clone)]
+struct Backing {
+    enc: Arc<crypto::Cipher>,
+    /// The opened targets. They are opened once per queue (up to [`MAX_TARGET_FDS`] files in
+    /// total), so queues don't contend on a single file description in the kernel. Every set
+    /// holds one file per target.
+    files: Arc<Vec<File>>,
+    /// Set of target files used by this handle.
+    fd_set: usize,
+    /// Memory holding the data of a ramdisk, which has no targets.
+    mem: Option<Arc<ramdisk::Ramdisk>>,
+    /// Whether the device stores nothing: reads return zeroes and writes are dropped.
+    null: bool,
+    /// How the device is spread over the targets.
+    geometry: geometry::Geometry,
+    /// Health of the targets, if they are mirrors of each other.
+    mirror: Option<Arc<mirror::Mirror>>,
+    /// Whether all targets are opened for direct IO.
+    direct: bool,
+    /// How discards are handled, if they are supported at all.
+    discard: Option<discard::DiscardPolicy>,
+    /// In audit mode, the target is opened read only, and all writes go to this overlay instead.
+    overlay: Option<Arc<overlay::Overlay>>,
+    /// Whether the device is read only, because it is being rescued.
+    read_only: bool,
+    /// Size of the base image, if the device has one. Its files follow the ones of the targets
+    /// in every set.
+    base_size: Option<u64>,
+    /// Logical block size of the device in bytes.
+    logical_block_size: u64,
+    /// Logical block size of the targets, if it is larger than the one of the device. IO which
+    /// is not aligned to it goes through a bounce buffer.
+    rmw_block: Option<u64>,
+    /// Locks serializing writes to target blocks, if blocks are emulated.
+    block_locks: Arc<rmw::BlockLocks>,
+    /// Extents which returned an unrecoverable error, once the device is created.
+    errors: Option<Arc<error_map::ErrorMap>>,
+    /// Whether reads of extents in the error map fail without going to the target.
+    fail_fast: bool,
+    /// Size of the device in bytes.
+    size: u64,
+    /// Allocation of chunks on the target, if it is thin provisioned.
+    thin: Option<Arc<thin::ThinMap>>,
+    /// Whether IO buffers are poisoned before reads and after other requests complete, to
+    /// catch miscomputed lengths.
+    poison: bool,
+    /// Latency to keep the target below by limiting the amount of IOs in flight, if any.
+    target_latency: Option<Duration>,
+    /// Window in which flushes on a queue are coalesced in a single sync, if any.
+    flush_window: Option<Duration>,
+    /// Window in which small discards on a queue are batched, if any.
+    discard_window: Option<Duration>,
+    /// CPUs the thread of every queue is pinned to, empty if the queues are not pinned.
+    queue_cpus: Arc<Vec<Vec<u32>>>,
+}
+
+impl Backing {
     fn as_queue_handler(self) -> impl FnOnce(u16, &UblkDev) + Send + Sync + Clone + 'static {
         move |queue_id, dev| self.queue_handler(queue_id, dev)
     }
 
-    fn new(path: PathBuf) -> Result<(Self, std::fs::File), io::Error> {
-        let target = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(O_DIRECT)
-            .open(&path)?;
+    fn new(config: BackingConfig) -> Result<Self, io::Error> {
+        let BackingConfig {
+            paths,
+            nr_queues,
+            discard,
+            audit,
+            rescue,
+            size,
+            thin,
+            base,
+            raid,
+            slice,
+            resync,
+            logical_block_size,
+            target_latency,
+            cipher_backend,
+            key,
+        } = config;
+        // The base image is opened like a target.
+        if paths.len() + base.is_some() as usize > MAX_TARGET_FDS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("at most {MAX_TARGET_FDS} targets, including a base image, are supported"),
+            ));
+        }
+
+        let ramdisk = ramdisk::is_ramdisk(&paths);
+        let null = is_null_target(&paths);
+        if (ramdisk || null)
+            && (thin || raid.is_some() || discard == Some(discard::DiscardPolicy::Passdown))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a ramdisk or null device can't be thin provisioned, combined with other targets or pass discards down",
+            ));
+        }
+        // A ramdisk or null device has no files to open.
+        let paths = if ramdisk || null { Vec::new() } else { paths };
+
+        // Writes in audit mode go to the overlay, and a rescued device is read only.
+        let writable = !audit && !rescue;
+        let mut targets = Vec::with_capacity(paths.len());
+        for path in &paths {
+            let target = target::open(path, writable)?;
+            if discard == Some(discard::DiscardPolicy::Passdown)
+                && !target.file.metadata()?.file_type().is_block_device()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "discard passdown requires a block device target",
+                ));
+            }
+            // Zones must be written sequentially, while the device is written at random.
+            if target.file.metadata()?.file_type().is_block_device()
+                && layout::Layout::new(&target.file)
+                    .map_err(io::Error::other)?
+                    .zone_size
+                    != 0
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is a zoned device, which is not supported",
+                        path.display()
+                    ),
+                ));
+            }
+            targets.push(target);
+        }
+        let direct = targets.iter().all(|target| target.direct);
 
-        // TODO: temp for testing
-        const KEY: [u8; 32] = [
-            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
-            24, 25, 26, 27, 28, 29, 30, 31,
-        ];
-        let cipher_1 = Aes128::new(GenericArray::from_slice(&KEY[..16]));
-        let cipher_2 = Aes128::new(GenericArray::from_slice(&KEY[16..]));
-        let enc = Arc::new(Xts128::<Aes128>::new(cipher_1, cipher_2));
+        // The base image is only read, all changes to it are stored on the target.
+        let base = match base {
+            Some(path) => {
+                let target = target::open(&path, false)?;
+                let size = layout::Layout::new(&target.file)
+                    .map_err(io::Error::other)?
+                    .size;
+                Some((path, target, size))
+            }
+            None => None,
+        };
+        let base_size = base.as_ref().map(|(_, _, size)| *size);
+
+        // Direct IO on a block device must be aligned to its logical block size. Buffered IO is
+        // aligned by the page cache, and files accept IO aligned to the block size of the
+        // filesystem device, which is not known here, so only block devices are considered.
+        let mut target_block_size = 512;
+        let base_target = base.as_ref().map(|(_, target, _)| target);
+        for target in targets
+            .iter()
+            .chain(base_target)
+            .filter(|target| target.direct)
+        {
+            if target.file.metadata()?.file_type().is_block_device() {
+                let layout = layout::Layout::new(&target.file).map_err(io::Error::other)?;
+                target_block_size = target_block_size.max(layout.logical_block_size);
+            }
+        }
+        let rmw_block = (target_block_size > logical_block_size).then_some(target_block_size);
+        if let Some(block) = rmw_block {
+            log::warn!(
+                "targets have {block} byte blocks, emulating {logical_block_size} byte blocks"
+            );
+        }
 
-        Ok((Backing { enc }, target))
+        let mut mirror = None;
+        match &raid {
+            Some(geometry::Raid::Striped(stripe_size))
+                if !stripe_size.is_power_of_two() || *stripe_size < 4096 =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "stripe size must be a power of 2 of at least 4096",
+                ));
+            }
+            Some(geometry::Raid::Mirrored(_)) if targets.len() < 2 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "mirroring requires at least 2 targets",
+                ));
+            }
+            Some(geometry::Raid::Mirrored(write_mostly)) => {
+                // Paths are compared after resolving them, so a target can be named either way.
+                let canonical =
+                    |path: &PathBuf| std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                let legs: Vec<PathBuf> = paths.iter().map(canonical).collect();
+                for path in write_mostly {
+                    if !legs.contains(&canonical(path)) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("write mostly target {} is not a target", path.display()),
+                        ));
+                    }
+                }
+                let write_mostly = legs
+                    .iter()
+                    .map(|leg| write_mostly.iter().map(canonical).any(|path| path == *leg))
+                    .collect();
+                mirror = Some(Arc::new(mirror::Mirror::new(legs, write_mostly)?));
+            }
+            _ => {}
+        }
+
+        let geometry = match slice {
+            Some(_) if targets.len() != 1 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "an offset or size limit requires a single target",
+                ));
+            }
+            Some(slice) => geometry::Geometry::slice(&targets[0], &paths[0], slice)?,
+            None => geometry::Geometry::new(&targets, &paths, &raid)?,
+        };
+        if let (Some(mirror), true) = (&mirror, resync) {
+            let files: Vec<&File> = targets.iter().map(|target| &target.file).collect();
+            mirror.resync(&files, geometry.size().unwrap_or(0))?;
+        }
+
+        let enc = Arc::new(crypto::Cipher::new(cipher_backend, &key)?);
+
+        let thin = if thin {
+            // A regular file grows as chunks are allocated, a block device has a fixed size.
+            let target_size = match geometry {
+                geometry::Geometry::Single
+                    if targets[0].file.metadata()?.file_type().is_block_device() =>
+                {
+                    Some(
+                        layout::Layout::new(&targets[0].file)
+                            .map_err(io::Error::other)?
+                            .size,
+                    )
+                }
+                _ => geometry.size(),
+            };
+            let size = base_size
+                .map(|size| size - size % logical_block_size)
+                .or(size);
+            let base_id = match &base {
+                Some((_, target, size)) => Some(thin::BaseId::new(&target.file, *size)?),
+                None => None,
+            };
+            // The mapping is stored on the first target.
+            let map = thin::ThinMap::open(&targets[0].file, size, target_size, base_id, rescue)?;
+            map.check_base(base_id)?;
+            if let geometry::Geometry::Linear(sizes) = &geometry {
+                if map.data_start() > sizes[0] {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the mapping table does not fit on the first target",
+                    ));
+                }
+            }
+            Some(Arc::new(map))
+        } else {
+            None
+        };
+        let size = match (&thin, geometry.size()) {
+            (Some(thin), _) => thin.size(),
+            (None, None) => size.unwrap_or(DEFAULT_DEV_SIZE),
+            (None, Some(total)) => {
+                let size = size.unwrap_or(total);
+                if size > total {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("device size exceeds the {total} bytes of the targets"),
+                    ));
+                }
+                size
+            }
+        };
+
+        // The device holds whole logical blocks.
+        let size = size - size % logical_block_size;
+
+        // Every set holds the files of the targets, followed by the one of the base image.
+        let files_per_set = targets.len() + base.is_some() as usize;
+        let nr_sets = (nr_queues as usize).clamp(1, MAX_TARGET_FDS / files_per_set.max(1));
+        let mut files = Vec::with_capacity(nr_sets * files_per_set);
+        for _ in 1..nr_sets {
+            for (target, path) in targets.iter().zip(&paths) {
+                files.push(target::reopen(path, writable, target.direct)?);
+            }
+            if let Some((path, target, _)) = &base {
+                files.push(target::reopen(path, false, target.direct)?);
+            }
+        }
+        let first_set = targets
+            .into_iter()
+            .chain(base.map(|(_, target, _)| target))
+            .map(|target| target.file);
+        files.splice(0..0, first_set);
+
+        let mem = if ramdisk {
+            Some(Arc::new(ramdisk::Ramdisk::new(size)?))
+        } else {
+            None
+        };
+
+        Ok(Backing {
+            enc,
+            mem,
+            null,
+            files: Arc::new(files),
+            fd_set: 0,
+            geometry,
+            mirror,
+            direct,
+            discard,
+            overlay: audit.then(Default::default),
+            read_only: rescue,
+            base_size,
+            logical_block_size,
+            rmw_block,
+            block_locks: Default::default(),
+            errors: None,
+            fail_fast: false,
+            size,
+            thin,
+            poison: false,
+            target_latency,
+            flush_window: None,
+            discard_window: None,
+            queue_cpus: Arc::new(topology::queue_cpus(nr_queues)),
+        })
+    }
+
+    /// Pass the expected lifetime of written data to the targets. The hint is kept per inode, so
+    /// setting it on one file of every target covers all sets. Targets which don't support hints
+    /// are used without them.
+    fn set_write_life_hint(&self, hint: kernel::WriteLifeHint) {
+        for file in self.files.iter().take(self.geometry.nr_targets()) {
+            if let Err(e) = kernel::set_write_life_hint(file.as_fd(), hint) {
+                log::warn!("could not set write life hint {hint:?} on target: {e}");
+            }
+        }
+    }
+
+    /// Whether completed writes to the targets may be lost on a crash until they are synced. Only
+    /// direct writes to block devices without a write cache are durable once they complete.
+    fn targets_cache_writes(&self) -> bool {
+        if !self.direct {
+            return true;
+        }
+        self.files
+            .iter()
+            .take(self.geometry.nr_targets())
+            .any(|file| match file.metadata() {
+                Ok(meta) if meta.file_type().is_block_device() => {
+                    sysfs::has_write_cache(meta.rdev()).unwrap_or_else(|e| {
+                        log::warn!("could not find the write cache mode of a target: {e}");
+                        true
+                    })
+                }
+                _ => true,
+            })
+    }
+
+    /// Usable bytes of the IO buffer of a tag. In debug poison mode the rest is a guard page.
+    fn io_buf_bytes(&self) -> u32 {
+        if self.poison {
+            IO_BUF_BYTES - GUARD_BYTES
+        } else {
+            IO_BUF_BYTES
+        }
+    }
+
+    /// Physical block size of the device in bytes.
+    fn physical_block_size(&self) -> u64 {
+        self.rmw_block.unwrap_or(self.logical_block_size)
+    }
+
+    /// Amount of files in a set.
+    fn files_per_set(&self) -> usize {
+        self.geometry.nr_targets() + self.base_size.is_some() as usize
+    }
+
+    /// Index of the base image, used as a target for reads.
+    fn base_target(&self) -> usize {
+        self.geometry.nr_targets()
+    }
+
+    /// File of a target used by this handle.
+    fn file(&self, target: usize) -> &File {
+        &self.files[self.fd_set * self.files_per_set() + target]
+    }
+
+    /// File of a target used by this handle, as registered in the queue ring.
+    fn fixed_fd(&self, target: usize) -> Result<types::Fixed, sqe::SqeError> {
+        // The ublk character device is the first registered file.
+        let index = 1 + self.fd_set * self.files_per_set() + target;
+        sqe::check_fd(index as u32, self.files.len())
+    }
+
+    /// Size in which IO on the device is split, so every piece maps to a contiguous range on a
+    /// single target.
+    fn split_size(&self) -> u64 {
+        self.geometry.split_size(self.thin.is_some())
+    }
+
+    /// Translate an offset on the device to a target and the offset on that target. Returns None
+    /// if nothing is stored for the offset on the targets yet. Writes to a thin device must use
+    /// [`allocate_chunk`] instead.
+    fn translate(&self, off: u64) -> io::Result<Option<(usize, u64)>> {
+        let off = match &self.thin {
+            None => off,
+            Some(thin) => match thin.lookup(off)? {
+                Some(off) => off,
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(self.geometry.route(off)))
     }
 
     fn queue_handler(&self, queue_id: u16, dev: &UblkDev) {
+        if let Some(cpus) = self.queue_cpus.get(queue_id as usize) {
+            match kernel::set_cpu_affinity(cpus) {
+                Ok(()) => log::debug!("queue {queue_id} runs on CPUs {cpus:?}"),
+                Err(e) => log::warn!("could not pin queue {queue_id} to CPUs {cpus:?}: {e}"),
+            }
+        }
+        // Spread the queues over the opened target files.
+        let backing = &Backing {
+            fd_set: queue_id as usize % (self.files.len() / self.files_per_set()).max(1),
+            ..self.clone()
+        };
         let queue = Rc::new(UblkQueue::new(queue_id, dev).unwrap());
         let exe = Executor::new(dev.get_nr_ios());
 
         let depth = dev.dev_info.queue_depth;
+        if self.poison {
+            set_guard_pages(&queue, depth, ProtFlags::PROT_NONE);
+        }
+        let depth_controller = self
+            .target_latency
+            .map(|target| Rc::new(aqm::DepthController::new(target, depth as u32)));
+        let flush_coalescer = self
+            .flush_window
+            .map(|window| Rc::new(flush::FlushCoalescer::new(window)));
+        let discard_batcher = self
+            .discard_window
+            .map(|window| Rc::new(discard::DiscardBatcher::new(window)));
 
         for tag in 0..depth as u16 {
             let queue = queue.clone();
+            let depth_controller = depth_controller.clone();
+            let flush_coalescer = flush_coalescer.clone();
+            let discard_batcher = discard_batcher.clone();
             exe.spawn(tag as u16, async move {
                 let buf_addr = queue.get_io_buf_addr(tag);
                 // This MUST be the first command submitted.
                 let mut cmd_op = UBLK_IO_FETCH_REQ;
                 let mut res = 0;
+                let mut seq: u16 = 0;
                 loop {
                     let cmd_res = queue.submit_io_cmd(tag, cmd_op, buf_addr, res).await;
                     if cmd_res == UBLK_IO_RES_ABORT {
                         break;
                     }
 
-                    res = handle_io_cmd(&queue, tag, self).await;
+                    let trace = trace::TraceId::new(queue_id, tag, seq);
+                    seq = seq.wrapping_add(1);
+                    let start = Instant::now();
+                    res = handle_io_cmd(
+                        &queue,
+                        tag,
+                        trace,
+                        backing,
+                        depth_controller.as_deref(),
+                        flush_coalescer.as_deref(),
+                        discard_batcher.as_deref(),
+                    )
+                    .await;
+                    log::debug!("{trace}: completed with {res} in {:?}", start.elapsed());
+                    // The data of a read is copied to the kernel when its result is committed,
+                    // the buffer of any other request is not used anymore once it completes.
+                    let op = queue.get_iod(tag).op_flags & 0xff;
+                    if backing.poison && op != libublk::sys::UBLK_IO_OP_READ {
+                        // SAFETY: the IO buffer of a tag is IO_BUF_BYTES long, of which
+                        // io_buf_bytes are not a guard page.
+                        unsafe {
+                            std::ptr::write_bytes(
+                                buf_addr,
+                                POISON_BYTE,
+                                backing.io_buf_bytes() as usize,
+                            )
+                        };
+                    }
                     cmd_op = UBLK_IO_COMMIT_AND_FETCH_REQ;
                 }
             });
         }
 
         queue.wait_and_wake_io_tasks(&exe);
+        // The buffers are freed with the queue.
+        if self.poison {
+            set_guard_pages(&queue, depth, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE);
+        }
         // Sync version?
         //queue.wait_and_handle_io(|queue, tag, io_ctx| {
         //    let io_descriptor = queue.get_iod(tag);
@@ -292,133 +1941,1098 @@ impl Backing {
 }
 
 #[inline]
-fn prep_io_cmd_submission(io_descriptor: &libublk::sys::ublksrv_io_desc) -> i32 {
+fn prep_io_cmd_submission(io_descriptor: &libublk::sys::ublksrv_io_desc, backing: &Backing) -> i32 {
     let op = io_descriptor.op_flags & 0xff;
 
     match op {
-        libublk::sys::UBLK_IO_OP_FLUSH
-        | libublk::sys::UBLK_IO_OP_READ
-        | libublk::sys::UBLK_IO_OP_WRITE => 0,
+        libublk::sys::UBLK_IO_OP_WRITE | libublk::sys::UBLK_IO_OP_DISCARD if backing.read_only => {
+            EROFS
+        }
+        libublk::sys::UBLK_IO_OP_FLUSH => 0,
+        // The data has to fit in the IO buffer of the tag.
+        libublk::sys::UBLK_IO_OP_READ | libublk::sys::UBLK_IO_OP_WRITE
+            if (io_descriptor.nr_sectors as u64) << 9 <= backing.io_buf_bytes() as u64 =>
+        {
+            0
+        }
+        libublk::sys::UBLK_IO_OP_DISCARD if backing.discard.is_some() => 0,
         _ => EINVAL,
     }
 }
 
+/// Queue and handle of the device an IO to the targets is submitted with, and the user data
+/// of its completions.
+#[derive(Clone, Copy)]
+struct IoContext<'a, 'q> {
+    queue: &'a UblkQueue<'q>,
+    data: u64,
+    backing: &'a Backing,
+    depth_controller: Option<&'a aqm::DepthController>,
+}
+
 #[inline]
 fn submit_io_cmd(
+    ctx: IoContext<'_, '_>,
+    op: u32,
+    target: usize,
+    off: u64,
+    buf_addr: *mut u8,
+    bytes: u32,
+) -> Result<(), sqe::SqeError> {
+    let IoContext {
+        queue,
+        data,
+        backing,
+        ..
+    } = ctx;
+    let fd = backing.fixed_fd(target)?;
+    let off = sqe::check_offset(off, bytes as u64)?;
+
+    let sqe = match op {
+        // This also flushes the write cache of the device below the target, which a sync of
+        // just the page cache would not.
+        libublk::sys::UBLK_IO_OP_FLUSH => opcode::Fsync::new(fd)
+            .flags(types::FsyncFlags::DATASYNC)
+            .build(),
+        libublk::sys::UBLK_IO_OP_READ => opcode::Read::new(fd, buf_addr, bytes).offset(off).build(),
+        libublk::sys::UBLK_IO_OP_WRITE => {
+            opcode::Write::new(fd, buf_addr, bytes).offset(off).build()
+        }
+        _ => return Ok(()),
+    }
+    .flags(squeue::Flags::FIXED_FILE)
+    .user_data(data);
+
+    // SAFETY: buf_addr is in the IO buffer of the tag, which stays valid until the request
+    // completes, and the request only completes after this IO.
+    unsafe { sqe::push(&queue.q_ring, &sqe) }
+}
+
+/// Submit an IO to the target and wait for it to complete, retrying if the target returns
+/// EAGAIN.
+async fn submit_and_wait(
+    ctx: IoContext<'_, '_>,
+    op: u32,
+    target: usize,
+    off: u64,
+    buf_addr: *mut u8,
+    bytes: u32,
+) -> i32 {
+    let IoContext {
+        queue,
+        data,
+        depth_controller,
+        ..
+    } = ctx;
+    let mut res = EAGAIN;
+    for _ in 0..4 {
+        if let Some(controller) = depth_controller {
+            if let Err(e) = acquire_depth(queue, controller, data).await {
+                log::warn!(
+                    "{}: could not wait for the depth controller: {e}",
+                    trace::TraceId::from_user_data(queue.q_id, data)
+                );
+                res = e.errno();
+                if res != EAGAIN {
+                    return res;
+                }
+                continue;
+            }
+        }
+        let start = Instant::now();
+        res = match submit_io_cmd(ctx, op, target, off, buf_addr, bytes) {
+            Ok(()) => UringOpFuture { user_data: data }.await,
+            Err(e) => {
+                log::warn!(
+                    "{}: could not submit IO at offset {off}: {e}",
+                    trace::TraceId::from_user_data(queue.q_id, data)
+                );
+                e.errno()
+            }
+        };
+        if let Some(controller) = depth_controller {
+            controller.release(start.elapsed());
+        }
+        if res != EAGAIN {
+            return res;
+        }
+        log::debug!(
+            "{}: IO at offset {off} on target {target} returned EAGAIN, retrying",
+            trace::TraceId::from_user_data(queue.q_id, data)
+        );
+    }
+
+    res
+}
+
+/// Submit an IO to a target, emulating the logical block size of the device if the target has
+/// larger blocks. Reads and writes which are not aligned to the blocks of the target are done
+/// through an aligned bounce buffer, writes are a read-modify-write of the blocks they touch.
+async fn submit_target_io(
+    ctx: IoContext<'_, '_>,
+    op: u32,
+    target: usize,
+    off: u64,
+    buf_addr: *mut u8,
+    bytes: u32,
+) -> i32 {
+    let IoContext {
+        queue,
+        data,
+        backing,
+        ..
+    } = ctx;
+    let block = match backing.rmw_block {
+        Some(block)
+            if op == libublk::sys::UBLK_IO_OP_READ || op == libublk::sys::UBLK_IO_OP_WRITE =>
+        {
+            block
+        }
+        _ => return submit_and_wait(ctx, op, target, off, buf_addr, bytes).await,
+    };
+
+    let span = rmw::Span::new(off, bytes, block);
+    let aligned = span.is_aligned() && (buf_addr as u64).is_multiple_of(block);
+    if aligned && op == libublk::sys::UBLK_IO_OP_READ {
+        return submit_and_wait(ctx, op, target, off, buf_addr, bytes).await;
+    }
+
+    let _guard = if op == libublk::sys::UBLK_IO_OP_WRITE {
+        match lock_blocks(queue, &backing.block_locks, target, span.blocks(), data).await {
+            Ok(guard) => Some(guard),
+            Err(e) => {
+                log::warn!(
+                    "{}: could not wait for locked blocks: {e}",
+                    trace::TraceId::from_user_data(queue.q_id, data)
+                );
+                return e.errno();
+            }
+        }
+    } else {
+        None
+    };
+    if aligned {
+        return submit_and_wait(ctx, op, target, off, buf_addr, bytes).await;
+    }
+
+    let start = span.range.start;
+    let len = span.range.end - start;
+    let head = span.head;
+    let mut bounce = target::AlignedBuf::zeroed(len as usize);
+    // A write covering whole blocks only needs an aligned buffer, not the old data.
+    if op == libublk::sys::UBLK_IO_OP_READ || !span.is_aligned() {
+        let res = submit_and_wait(
+            ctx,
+            libublk::sys::UBLK_IO_OP_READ,
+            target,
+            start,
+            bounce.as_mut_ptr(),
+            len as u32,
+        )
+        .await;
+        if res < 0 {
+            return res;
+        }
+        if op == libublk::sys::UBLK_IO_OP_READ {
+            let read = span.done(res as usize);
+            // SAFETY: buf_addr points to at least bytes bytes in the IO buffer of the tag.
+            unsafe { std::ptr::copy_nonoverlapping(bounce[head..].as_ptr(), buf_addr, read) };
+            return read as i32;
+        }
+    }
+
+    // SAFETY: see above.
+    unsafe { std::ptr::copy_nonoverlapping(buf_addr, bounce[head..].as_mut_ptr(), bytes as usize) };
+    let res = submit_and_wait(ctx, op, target, start, bounce.as_mut_ptr(), len as u32).await;
+    if res < 0 {
+        return res;
+    }
+    span.done(res as usize) as i32
+}
+
+/// Translate an offset of a write on a thin device to an offset on the target, allocating the
+/// chunk holding it if needed. Only the reservation of a new chunk holds the lock of the map, the
+/// chunk is initialized and its table entry is stored through the queue ring. Returns a negative
+/// errno on failure.
+async fn allocate_chunk(
+    ctx: IoContext<'_, '_>,
+    thin: &thin::ThinMap,
+    off: u64,
+) -> Result<u64, i32> {
+    let IoContext { queue, data, .. } = ctx;
+    let chunk = loop {
+        match thin.reserve(off) {
+            Ok(thin::Reservation::Mapped(target_off)) => return Ok(target_off),
+            Ok(thin::Reservation::New(chunk)) => break chunk,
+            Ok(thin::Reservation::Busy) => {}
+            Err(e) => return Err(-e.raw_os_error().unwrap_or(-EIO)),
+        }
+        if let Err(e) = sleep(queue, CHUNK_WAIT, data).await {
+            return Err(e.errno());
+        }
+    };
+
+    let res = init_chunk(ctx, &chunk).await;
+    if res < 0 {
+        thin.release(chunk, false);
+        return Err(res);
+    }
+
+    let (table_off, mut block) = loop {
+        if let Some(block) = thin.table_block(&chunk) {
+            break block;
+        }
+        if let Err(e) = sleep(queue, CHUNK_WAIT, data).await {
+            thin.release(chunk, false);
+            return Err(e.errno());
+        }
+    };
+    // The table is at the start of the first target.
+    let res = submit_target_io(
+        ctx,
+        libublk::sys::UBLK_IO_OP_WRITE,
+        0,
+        table_off,
+        block.as_mut_ptr(),
+        block.len() as u32,
+    )
+    .await;
+    if res < 0 {
+        thin.release(chunk, true);
+        return Err(res);
+    }
+
+    let target_off = chunk.target_off + off % thin::CHUNK_SIZE;
+    thin.commit(chunk);
+    Ok(target_off)
+}
+
+/// Fill a newly reserved chunk on the targets with zeroes, as seen through the encryption, so
+/// the parts of it which are not written read as zeroes. If the device has a base image, the
+/// chunk is filled with the data of the base image instead. Returns a negative errno on failure.
+async fn init_chunk(ctx: IoContext<'_, '_>, chunk: &thin::NewChunk) -> i32 {
+    let backing = ctx.backing;
+    let mut buf = target::AlignedBuf::zeroed(thin::CHUNK_SIZE as usize);
+    if backing.base_size.is_some() {
+        // The end of the base image may be in the middle of the chunk, the rest stays zero.
+        let res = submit_target_io(
+            ctx,
+            libublk::sys::UBLK_IO_OP_READ,
+            backing.base_target(),
+            chunk.off,
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        )
+        .await;
+        if res < 0 {
+            return res;
+        }
+    }
+    if let Err(e) = backing.enc.encrypt_area(&mut buf, chunk.off >> 9) {
+        log::error!(
+            "{}: could not encrypt chunk at offset {}: {e}",
+            trace::TraceId::from_user_data(ctx.queue.q_id, ctx.data),
+            chunk.off
+        );
+        return EIO;
+    }
+    // Chunks are aligned to the targets, so a chunk is on a single target.
+    let (target, target_off) = backing.geometry.route(chunk.target_off);
+    let res = submit_target_io(
+        ctx,
+        libublk::sys::UBLK_IO_OP_WRITE,
+        target,
+        target_off,
+        buf.as_mut_ptr(),
+        buf.len() as u32,
+    )
+    .await;
+    if res < 0 {
+        return res;
+    }
+    if res as usize != buf.len() {
+        return EIO;
+    }
+    0
+}
+
+/// Change the protection of the guard page at the end of the IO buffer of every tag of a queue.
+/// The guard pages must be made accessible again before the queue frees the buffers.
+fn set_guard_pages(queue: &UblkQueue<'_>, depth: u16, prot: ProtFlags) {
+    for tag in 0..depth {
+        let guard = queue
+            .get_io_buf_addr(tag)
+            .wrapping_add((IO_BUF_BYTES - GUARD_BYTES) as usize);
+        // SAFETY: the IO buffer of a tag is IO_BUF_BYTES long and page aligned, so the guard is
+        // a whole page of it, which is not used for IO in debug poison mode. mprotect fails
+        // without changing anything otherwise.
+        if let Err(e) = unsafe { mprotect(guard.cast(), GUARD_BYTES as usize, prot) } {
+            log::warn!(
+                "could not protect the guard page of tag {tag} on queue {}: {e}",
+                queue.q_id
+            );
+        }
+    }
+}
+
+/// Wait until a range of blocks on a target can be locked.
+async fn lock_blocks<'a>(
     queue: &UblkQueue<'_>,
-    tag: u16,
-    io_descriptor: &libublk::sys::ublksrv_io_desc,
+    locks: &'a rmw::BlockLocks,
+    target: usize,
+    blocks: Range<u64>,
     data: u64,
-    backing: &Backing,
-) {
-    let op = io_descriptor.op_flags & 0xff;
-    // either start to handle or retry
-    let off = (io_descriptor.start_sector << 9) as u64;
-    let bytes = (io_descriptor.nr_sectors << 9) as u32;
-    let buf_addr = queue.get_io_buf_addr(tag);
+) -> Result<rmw::BlockGuard<'a>, sqe::SqeError> {
+    loop {
+        if let Some(guard) = locks.try_lock(target, blocks.clone()) {
+            return Ok(guard);
+        }
+        let ts = types::Timespec::new().nsec(BLOCK_LOCK_WAIT_NSEC);
+        let sqe = opcode::Timeout::new(&ts).build().user_data(data);
+        // SAFETY: ts lives until the timeout completes, as it is awaited below.
+        unsafe { sqe::push(&queue.q_ring, &sqe)? };
+        // This completes with ETIME once the timeout expires, which is expected.
+        UringOpFuture { user_data: data }.await;
+    }
+}
 
-    match op {
-        libublk::sys::UBLK_IO_OP_FLUSH => {
-            let sqe = &opcode::SyncFileRange::new(types::Fixed(1), bytes)
-                .offset(off)
-                .build()
-                .flags(squeue::Flags::FIXED_FILE)
-                .user_data(data);
-            unsafe {
-                queue
-                    .q_ring
-                    .borrow_mut()
-                    .submission()
-                    .push(sqe)
-                    .expect("flush submission fail");
-            }
-        }
-        libublk::sys::UBLK_IO_OP_READ => {
-            let sqe = &opcode::Read::new(types::Fixed(1), buf_addr, bytes)
-                .offset(off)
-                .build()
-                .flags(squeue::Flags::FIXED_FILE)
-                .user_data(data);
-            unsafe {
-                queue
-                    .q_ring
-                    .borrow_mut()
-                    .submission()
-                    .push(sqe)
-                    .expect("read submission fail");
+/// Submit an IO to the legs of a mirrored device. Writes go to every healthy leg at the same
+/// time, and succeed if at least one leg succeeds. Reads are served by a single leg, falling
+/// back to the next one if it fails, and the data read from that one is written back to the
+/// legs which failed. Legs which miss a write, or return too many read errors in a row, are
+/// marked as failed, but a leg returning EAGAIN is only busy, so the IO fails with it instead.
+async fn submit_mirrored(
+    ctx: IoContext<'_, '_>,
+    mirror: &mirror::Mirror,
+    op: u32,
+    off: u64,
+    buf_addr: *mut u8,
+    bytes: u32,
+) -> i32 {
+    let mut result = EIO;
+    let range = off..off + bytes as u64;
+    if op == libublk::sys::UBLK_IO_OP_READ {
+        let mut repair = None;
+        let mut bad_legs = Vec::new();
+        for leg in mirror.read_order() {
+            let res = submit_target_io(ctx, op, leg, off, buf_addr, bytes).await;
+            if res >= 0 {
+                mirror.read_succeeded(leg);
+                if res as u32 == bytes && repair.is_some() {
+                    repair_legs(ctx, mirror, leg, &bad_legs, off, buf_addr, bytes).await;
+                }
+                return res;
+            }
+            if res == EAGAIN {
+                result = res;
+                continue;
             }
+            // A failed read does not make the leg stale, so it is fine to keep using it if its
+            // failure can't be stored.
+            if mirror.read_failed(leg) {
+                let _ = fail_leg(ctx, mirror, leg, res).await;
+            } else {
+                bad_legs.push(leg);
+            }
+            // The range is locked before the data to repair it with is read. If a write to it
+            // is in progress, the leg is not repaired, as the data read could be stale.
+            if repair.is_none() {
+                repair = mirror.start_repair(range.clone());
+            }
+            result = res;
         }
-        libublk::sys::UBLK_IO_OP_WRITE => {
-            // Encrypt buffer first
-            let buf = unsafe { std::slice::from_raw_parts_mut(buf_addr, bytes as usize) };
-            backing.enc.encrypt_area(
-                buf,
-                512,
-                io_descriptor.start_sector as u128,
-                get_tweak_default,
+        return result;
+    }
+
+    let _write = loop {
+        if let Some(guard) = mirror.start_write(range.clone()) {
+            break guard;
+        }
+        if let Err(e) = sleep(ctx.queue, REPAIR_WAIT, ctx.data).await {
+            return e.errno();
+        }
+    };
+    let legs: Vec<usize> = mirror.healthy_legs().collect();
+    let results = join_all(
+        legs.iter()
+            .map(|leg| {
+                let ctx = IoContext {
+                    data: ctx.data | (*leg as u64 + 1) << TARGET_DATA_SHIFT,
+                    ..ctx
+                };
+                submit_target_io(ctx, op, *leg, off, buf_addr, bytes)
+            })
+            .collect(),
+    )
+    .await;
+
+    let mut written: Option<i32> = None;
+    let mut busy = false;
+    for (leg, res) in legs.into_iter().zip(results) {
+        if res == EAGAIN {
+            busy = true;
+        } else if res < 0 {
+            // A leg which missed a write is stale, and must not be used again.
+            if fail_leg(ctx, mirror, leg, res).await.is_err() {
+                return EIO;
+            }
+            result = res;
+        } else {
+            // A short write on one leg makes the whole write short.
+            written = Some(written.map_or(res, |w| w.min(res)));
+        }
+    }
+
+    if busy {
+        return EAGAIN;
+    }
+    written.unwrap_or(result)
+}
+
+/// Write the data read from leg `src` back to the legs which failed to read it. A leg which
+/// can't be written is left as is, it already returned an error for the range.
+async fn repair_legs(
+    ctx: IoContext<'_, '_>,
+    mirror: &mirror::Mirror,
+    src: usize,
+    legs: &[usize],
+    off: u64,
+    buf_addr: *mut u8,
+    bytes: u32,
+) {
+    let trace = trace::TraceId::from_user_data(ctx.queue.q_id, ctx.data);
+    let op = libublk::sys::UBLK_IO_OP_WRITE;
+    for &leg in legs.iter().filter(|leg| !mirror.is_failed(**leg)) {
+        let res = submit_target_io(ctx, op, leg, off, buf_addr, bytes).await;
+        if res as u32 == bytes {
+            log::warn!(
+                "{trace}: repaired {bytes} bytes at {off} of mirror leg {} from {}, {} repairs",
+                mirror.leg(leg).display(),
+                mirror.leg(src).display(),
+                mirror.healed()
+            );
+        } else {
+            log::error!(
+                "{trace}: could not repair {bytes} bytes at {off} of mirror leg {}: {res}",
+                mirror.leg(leg).display()
             );
-            let sqe = &opcode::Write::new(types::Fixed(1), buf_addr, bytes)
-                .offset(off)
-                .build()
-                .flags(squeue::Flags::FIXED_FILE)
-                .user_data(data);
-            unsafe {
-                queue
-                    .q_ring
-                    .borrow_mut()
-                    .submission()
-                    .push(sqe)
-                    .expect("write submission fail");
-            }
-        }
-        _ => {}
+        }
+    }
+}
+
+/// Fail a leg of a mirror after it returned error code res. The failure is stored on a worker
+/// thread first, as syncing it blocks. If that fails the leg is kept, and the IO which failed on
+/// it must fail as well.
+async fn fail_leg(
+    ctx: IoContext<'_, '_>,
+    mirror: &mirror::Mirror,
+    leg: usize,
+    res: i32,
+) -> io::Result<()> {
+    if mirror.is_failed(leg) {
+        return Ok(());
+    }
+    let path = mirror.leg(leg).to_path_buf();
+    if let Err(e) = run_blocking(ctx, move || mirror::store_marker(&path))
+        .await
+        .and_then(|stored| stored)
+    {
+        log::error!(
+            "could not store the failure of mirror leg {}: {e}",
+            mirror.leg(leg).display()
+        );
+        return Err(e);
+    }
+    mirror.mark_failed(leg, res);
+    Ok(())
+}
+
+/// Run futures at the same time on the current task, returning their outputs in order.
+async fn join_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+    let mut futures: Vec<Pin<Box<F>>> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(res) => *output = Some(res),
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+    outputs.into_iter().map(Option::unwrap).collect()
+}
+
+/// Handle a discard request. Small discards are batched with others arriving within the
+/// window of the batcher, if any, and the merged ranges are discarded one after the other.
+async fn handle_discard(
+    ctx: IoContext<'_, '_>,
+    trace: trace::TraceId,
+    io_descriptor: &libublk::sys::ublksrv_io_desc,
+    batcher: Option<&discard::DiscardBatcher>,
+) -> i32 {
+    let backing = ctx.backing;
+    let policy = match backing.discard {
+        None => return EINVAL,
+        Some(discard::DiscardPolicy::Ignore) => return 0,
+        Some(policy) => policy,
     };
+
+    let start = io_descriptor.start_sector << 9;
+    let end = start + ((io_descriptor.nr_sectors as u64) << 9);
+
+    if let Some(mem) = &backing.mem {
+        mem.discard(start, end - start);
+        return 0;
+    }
+
+    let batcher = match batcher {
+        Some(batcher) if end - start < discard::BATCH_MAX_BYTES => batcher,
+        _ => return discard_range(ctx, policy, start, end).await,
+    };
+    match batcher.join(start..end) {
+        discard::Role::Lead(batch) => {
+            // Give other discards the window to join. Failing to wait only shortens it.
+            let _ = sleep(ctx.queue, batcher.window(), ctx.data).await;
+            let mut res = 0;
+            for range in batcher.close(batch) {
+                res = discard_range(ctx, policy, range.start, range.end).await;
+                if res < 0 {
+                    break;
+                }
+            }
+            batcher.finish(batch, res);
+            res
+        }
+        discard::Role::Join(batch) => loop {
+            if let Some(res) = batcher.result(batch) {
+                log::debug!("{trace}: completed by discard batch {batch}");
+                break res;
+            }
+            if let Err(e) = sleep(ctx.queue, batcher.window(), ctx.data).await {
+                log::warn!("{trace}: could not wait for discard batch {batch}: {e}");
+            }
+        },
+    }
 }
 
-async fn handle_io_cmd(queue: &UblkQueue<'_>, tag: u16, backing: &Backing) -> i32 {
-    let iod = queue.get_iod(tag);
-    let op = iod.op_flags & 0xff;
-    let user_data = UblkIOCtx::build_user_data_async(tag as u16, op, 0);
-    let res = prep_io_cmd_submission(iod);
+/// Discard a range of the device. The part of the range on every target is discarded as a
+/// single contiguous range, split in chunks of at most [`DISCARD_CHUNK_BYTES`] which are
+/// processed one after the other. Targets are discarded at the same time.
+async fn discard_range(
+    ctx: IoContext<'_, '_>,
+    policy: discard::DiscardPolicy,
+    start: u64,
+    end: u64,
+) -> i32 {
+    let backing = ctx.backing;
+    if policy == discard::DiscardPolicy::Zero {
+        let chunk_size = DISCARD_CHUNK_BYTES.min(backing.split_size());
+        for (off, len) in thin::split(start, end - start, chunk_size) {
+            let res = write_zeroes(ctx, off, len).await;
+            if res < 0 {
+                return res;
+            }
+        }
+        return 0;
+    }
+
+    // Consecutive stripes on a target are adjacent on it, so this is a single range per target,
+    // even for a striped device.
+    let mut ranges: Vec<Vec<Range<u64>>> = vec![Vec::new(); backing.geometry.nr_targets()];
+    for (off, len) in thin::split(start, end - start, backing.split_size()) {
+        let (target, off) = backing.geometry.route(off);
+        match ranges[target].last_mut() {
+            Some(range) if range.end == off => range.end += len,
+            _ => ranges[target].push(off..off + len),
+        }
+    }
+    // A mirrored range is discarded on every healthy leg.
+    let targets: Vec<(usize, Vec<Range<u64>>)> = match &backing.mirror {
+        Some(mirror) => mirror
+            .healthy_legs()
+            .map(|leg| (leg, ranges[0].clone()))
+            .collect(),
+        None => ranges
+            .into_iter()
+            .enumerate()
+            .filter(|(_, ranges)| !ranges.is_empty())
+            .collect(),
+    };
+
+    let results = join_all(
+        targets
+            .into_iter()
+            .map(|(target, ranges)| {
+                let ctx = IoContext {
+                    data: ctx.data | (target as u64 + 1) << TARGET_DATA_SHIFT,
+                    ..ctx
+                };
+                discard_target(ctx, policy, target, ranges)
+            })
+            .collect(),
+    )
+    .await;
+    results.into_iter().find(|res| *res < 0).unwrap_or(0)
+}
+
+/// Discard ranges of a target, one chunk after the other.
+async fn discard_target(
+    ctx: IoContext<'_, '_>,
+    policy: discard::DiscardPolicy,
+    target: usize,
+    ranges: Vec<Range<u64>>,
+) -> i32 {
+    let IoContext {
+        queue,
+        data,
+        backing,
+        ..
+    } = ctx;
+    for range in ranges {
+        for (off, len) in thin::split(range.start, range.end - range.start, DISCARD_CHUNK_BYTES) {
+            if policy == discard::DiscardPolicy::Passdown {
+                let res = passdown_discard(ctx, target, off, len).await;
+                if res < 0 {
+                    return res;
+                }
+                continue;
+            }
+
+            // The remaining policy is implemented with fallocate.
+            let mode = policy.fallocate_mode().unwrap();
+            let mut res = EAGAIN;
+            for _ in 0..4 {
+                let sqe = match (backing.fixed_fd(target), sqe::check_offset(off, len)) {
+                    (Ok(fd), Ok(off)) => opcode::Fallocate::new(fd, len)
+                        .offset(off)
+                        .mode(mode.bits())
+                        .build()
+                        .flags(squeue::Flags::FIXED_FILE)
+                        .user_data(data),
+                    (Err(e), _) | (_, Err(e)) => {
+                        log::error!(
+                            "{}: invalid discard at offset {off}: {e}",
+                            trace::TraceId::from_user_data(queue.q_id, data)
+                        );
+                        return e.errno();
+                    }
+                };
+                // SAFETY: fallocate does not reference any buffers.
+                res = match unsafe { sqe::push(&queue.q_ring, &sqe) } {
+                    Ok(()) => UringOpFuture { user_data: data }.await,
+                    Err(e) => e.errno(),
+                };
+                if res != EAGAIN {
+                    break;
+                }
+            }
+            if res < 0 {
+                return res;
+            }
+        }
+    }
+
+    0
+}
+
+/// Discard a range of a block device target with BLKDISCARD. The ioctl blocks until the device
+/// is done, so it runs on a worker thread.
+async fn passdown_discard(ctx: IoContext<'_, '_>, target: usize, off: u64, len: u64) -> i32 {
+    let trace = trace::TraceId::from_user_data(ctx.queue.q_id, ctx.data);
+    let file = match ctx.backing.file(target).try_clone() {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("{trace}: could not set up discard: {e}");
+            return EIO;
+        }
+    };
+
+    let res = run_blocking(ctx, move || {
+        let range = [off, len];
+        // SAFETY: ioctl on a valid file descriptor with a pointer to a valid range.
+        unsafe { kernel::ioctl_blkdiscard(file.as_raw_fd(), &range) }
+    })
+    .await;
+    match res {
+        Ok(Ok(_)) => 0,
+        Ok(Err(e)) => {
+            log::error!("{trace}: discard of {len} bytes at {off} failed: {e}");
+            EIO
+        }
+        Err(e) => {
+            log::error!("{trace}: could not run discard: {e}");
+            EIO
+        }
+    }
+}
+
+/// Run blocking work on a separate thread, which signals an eventfd once it is done. The queue
+/// keeps serving other requests while it waits for the eventfd.
+async fn run_blocking<T: Send + 'static>(
+    ctx: IoContext<'_, '_>,
+    work: impl FnOnce() -> T + Send + 'static,
+) -> io::Result<T> {
+    let done = kernel::eventfd()?;
+    let notify = done.try_clone()?;
+
+    let worker = std::thread::spawn(move || {
+        let res = work();
+        // If this fails, the queue blocks until the thread exits instead, see below.
+        let _ = File::from(notify).write_all(&1u64.to_ne_bytes());
+        res
+    });
+
+    let mut count = [0u8; 8];
+    let sqe = opcode::Read::new(types::Fd(done.as_raw_fd()), count.as_mut_ptr(), 8)
+        .build()
+        .user_data(ctx.data);
+    // SAFETY: count lives until the read completes, as it is awaited below.
+    let res = match unsafe { sqe::push(&ctx.queue.q_ring, &sqe) } {
+        Ok(()) => {
+            UringOpFuture {
+                user_data: ctx.data,
+            }
+            .await
+        }
+        Err(e) => e.errno(),
+    };
     if res < 0 {
-        return res;
+        log::warn!(
+            "{}: could not wait for a worker on the queue: {res}, blocking instead",
+            trace::TraceId::from_user_data(ctx.queue.q_id, ctx.data)
+        );
     }
 
-    for _ in 0..4 {
-        submit_io_cmd(queue, tag, iod, user_data, backing);
-        let res = UringOpFuture { user_data }.await;
-        if res != EAGAIN {
-            if res >= 0 {
-                decrypt_if_needed(queue, tag, iod, backing);
+    // The worker is done once the eventfd is signaled. If waiting for that failed, blocking the
+    // queue is the only option left.
+    worker
+        .join()
+        .map_err(|_| io::Error::other("worker thread panicked"))
+}
+
+/// Write `len` bytes of zeroes at `start` of the device, which must not cross a target or stripe
+/// boundary. The zeroes are encrypted like any other data, so they read back as zeroes.
+async fn write_zeroes(ctx: IoContext<'_, '_>, start: u64, len: u64) -> i32 {
+    let backing = ctx.backing;
+    let op = libublk::sys::UBLK_IO_OP_WRITE;
+    let mut buf = target::AlignedBuf::zeroed(len.min(ZERO_CHUNK_BYTES) as usize);
+    for (off, len) in thin::split(start, len, ZERO_CHUNK_BYTES) {
+        let buf = &mut buf[..len as usize];
+        buf.fill(0);
+        if let Err(e) = backing.enc.encrypt_area(buf, off >> 9) {
+            log::error!(
+                "{}: could not encrypt zeroes: {e}",
+                trace::TraceId::from_user_data(ctx.queue.q_id, ctx.data)
+            );
+            return EIO;
+        }
+        let (target, target_off) = backing.geometry.route(off);
+        let res = match &backing.mirror {
+            Some(mirror) => {
+                submit_mirrored(ctx, mirror, op, target_off, buf.as_mut_ptr(), len as u32).await
+            }
+            None => {
+                submit_target_io(ctx, op, target, target_off, buf.as_mut_ptr(), len as u32).await
             }
+        };
+        if res < 0 {
             return res;
         }
+        if (res as u64) < len {
+            return EIO;
+        }
     }
+    0
+}
+
+/// Wait until the depth controller allows another IO to be submitted to the target.
+async fn acquire_depth(
+    queue: &UblkQueue<'_>,
+    controller: &aqm::DepthController,
+    data: u64,
+) -> Result<(), sqe::SqeError> {
+    while !controller.try_acquire() {
+        let ts = types::Timespec::new().nsec(DEPTH_WAIT_NSEC);
+        let sqe = opcode::Timeout::new(&ts).build().user_data(data);
+        // SAFETY: ts lives until the timeout completes, as it is awaited below.
+        unsafe { sqe::push(&queue.q_ring, &sqe)? };
+        // This completes with ETIME once the timeout expires, which is expected.
+        UringOpFuture { user_data: data }.await;
+    }
+
+    Ok(())
+}
+
+/// Sync all targets, so every write completed before is durable.
+async fn flush_targets(ctx: IoContext<'_, '_>, buf_addr: *mut u8) -> i32 {
+    let backing = ctx.backing;
+    let op = libublk::sys::UBLK_IO_OP_FLUSH;
+    if let Some(mirror) = &backing.mirror {
+        return submit_mirrored(ctx, mirror, op, 0, buf_addr, 0)
+            .await
+            .min(0);
+    }
+    for target in 0..backing.geometry.nr_targets() {
+        let res = submit_and_wait(ctx, op, target, 0, buf_addr, 0).await;
+        if res < 0 {
+            return res;
+        }
+    }
+    0
+}
 
-    return EAGAIN;
+/// Wait for a duration on the queue ring.
+async fn sleep(queue: &UblkQueue<'_>, duration: Duration, data: u64) -> Result<(), sqe::SqeError> {
+    let ts = types::Timespec::from(duration);
+    let sqe = opcode::Timeout::new(&ts).build().user_data(data);
+    // SAFETY: ts lives until the timeout completes, as it is awaited below.
+    unsafe { sqe::push(&queue.q_ring, &sqe)? };
+    // This completes with ETIME once the timeout expires, which is expected.
+    UringOpFuture { user_data: data }.await;
+    Ok(())
 }
 
-fn decrypt_if_needed(
+async fn handle_io_cmd(
     queue: &UblkQueue<'_>,
     tag: u16,
-    io_descriptor: &libublk::sys::ublksrv_io_desc,
+    trace: trace::TraceId,
     backing: &Backing,
-) {
-    let op = io_descriptor.op_flags & 0xff;
+    depth_controller: Option<&aqm::DepthController>,
+    flush_coalescer: Option<&flush::FlushCoalescer>,
+    discard_batcher: Option<&discard::DiscardBatcher>,
+) -> i32 {
+    let iod = queue.get_iod(tag);
+    let op = iod.op_flags & 0xff;
+    log::debug!(
+        "{trace}: op {op} of {} sectors at sector {}",
+        iod.nr_sectors,
+        iod.start_sector
+    );
+    let user_data = UblkIOCtx::build_user_data_async(tag as u16, op, trace.seq() as u32);
+    let res = prep_io_cmd_submission(iod, backing);
+    if res < 0 {
+        return res;
+    }
 
-    if op != libublk::sys::UBLK_IO_OP_READ {
-        return;
+    if backing.null {
+        // Complete right away, so only the cost of vblock itself is measured.
+        let bytes = (iod.nr_sectors << 9) as usize;
+        return match op {
+            libublk::sys::UBLK_IO_OP_READ => {
+                // SAFETY: the request fits in the IO buffer of the tag, as checked above.
+                unsafe { std::ptr::write_bytes(queue.get_io_buf_addr(tag), 0, bytes) };
+                bytes as i32
+            }
+            libublk::sys::UBLK_IO_OP_WRITE => bytes as i32,
+            _ => 0,
+        };
     }
 
-    let bytes = (io_descriptor.nr_sectors << 9) as u32;
+    let ctx = IoContext {
+        queue,
+        data: user_data,
+        backing,
+        depth_controller,
+    };
+    if op == libublk::sys::UBLK_IO_OP_DISCARD {
+        return handle_discard(ctx, trace, iod, discard_batcher).await;
+    }
+
+    if let Some(overlay) = &backing.overlay {
+        match op {
+            // Nothing is ever written to the target, so there is nothing to flush.
+            libublk::sys::UBLK_IO_OP_FLUSH => return 0,
+            libublk::sys::UBLK_IO_OP_WRITE => {
+                let bytes = (iod.nr_sectors << 9) as usize;
+                let buf = unsafe { std::slice::from_raw_parts(queue.get_io_buf_addr(tag), bytes) };
+                overlay.write(iod.start_sector, buf);
+                return bytes as i32;
+            }
+            _ => {}
+        }
+    }
+
+    // A ramdisk lives in the memory of this process, next to the key, so its data is not
+    // encrypted. This also keeps fresh and discarded memory reading as zeroes.
+    let encrypted = backing.mem.is_none();
+    if encrypted && op == libublk::sys::UBLK_IO_OP_WRITE {
+        // Encrypt buffer first. This is done once, so a retry doesn't encrypt the data again.
+        let bytes = (iod.nr_sectors << 9) as usize;
+        let buf = unsafe { std::slice::from_raw_parts_mut(queue.get_io_buf_addr(tag), bytes) };
+        if let Err(e) = backing.enc.encrypt_area(buf, iod.start_sector) {
+            log::error!("{trace}: could not encrypt data: {e}");
+            return EIO;
+        }
+    }
+
+    let start = iod.start_sector << 9;
+    let len = (iod.nr_sectors as u64) << 9;
     let buf_addr = queue.get_io_buf_addr(tag);
-    let buf = unsafe { std::slice::from_raw_parts_mut(buf_addr, bytes as usize) };
-
-    // Decrypt buffer
-    backing.enc.decrypt_area(
-        buf,
-        512,
-        io_descriptor.start_sector as u128,
-        get_tweak_default,
-    );
+
+    if backing.poison && op == libublk::sys::UBLK_IO_OP_READ {
+        // The whole buffer is poisoned, not just the part used by this read, so a read beyond
+        // the request also returns the poison rather than data of an earlier request.
+        // SAFETY: the IO buffer of a tag is IO_BUF_BYTES long, of which io_buf_bytes are not a
+        // guard page.
+        unsafe { std::ptr::write_bytes(buf_addr, POISON_BYTE, backing.io_buf_bytes() as usize) };
+    }
+
+    if op == libublk::sys::UBLK_IO_OP_FLUSH {
+        // A ramdisk is never persisted.
+        if backing.mem.is_some() {
+            return 0;
+        }
+        let Some(coalescer) = flush_coalescer else {
+            return flush_targets(ctx, buf_addr).await;
+        };
+        return match coalescer.join() {
+            flush::Role::Lead(batch) => {
+                // Give other flushes the window to join. Failing to wait only shortens it.
+                let _ = sleep(queue, coalescer.window(), user_data).await;
+                coalescer.close(batch);
+                let res = flush_targets(ctx, buf_addr).await;
+                coalescer.finish(batch, res);
+                res
+            }
+            flush::Role::Join(batch) => loop {
+                if let Some(res) = coalescer.result(batch) {
+                    log::debug!("{trace}: completed by flush batch {batch}");
+                    break res;
+                }
+                if let Err(e) = sleep(queue, coalescer.window(), user_data).await {
+                    log::warn!("{trace}: could not wait for flush batch {batch}: {e}");
+                }
+            },
+        };
+    }
+
+    let mut done = 0;
+    for (off, piece_len) in thin::split(start, len, backing.split_size()) {
+        let bytes = match sqe::check_buf(off - start, piece_len, backing.io_buf_bytes()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::error!("{trace}: invalid IO at offset {off}: {e}");
+                return e.errno();
+            }
+        };
+        // SAFETY: the piece is within the IO buffer of the tag, as checked above.
+        let piece_addr = unsafe { buf_addr.add((off - start) as usize) };
+        if backing.fail_fast && op == libublk::sys::UBLK_IO_OP_READ {
+            if let Some(errors) = &backing.errors {
+                if errors.contains(off, piece_len) {
+                    return EIO;
+                }
+            }
+        }
+        let location = match &backing.thin {
+            Some(thin) if op == libublk::sys::UBLK_IO_OP_WRITE => {
+                match allocate_chunk(ctx, thin, off).await {
+                    Ok(target_off) => Ok(Some(backing.geometry.route(target_off))),
+                    Err(res) => {
+                        log::error!("{trace}: could not allocate a chunk at offset {off}: {res}");
+                        return res;
+                    }
+                }
+            }
+            _ => backing.translate(off),
+        };
+        let (target, target_off) = match location {
+            Ok(Some(location)) => location,
+            Ok(None) => {
+                // Unallocated chunks read from the base image, which is not encrypted.
+                let res = match backing.base_size {
+                    Some(_) => {
+                        submit_target_io(ctx, op, backing.base_target(), off, piece_addr, bytes)
+                            .await
+                    }
+                    None => 0,
+                };
+                if res < 0 {
+                    log::error!("{trace}: could not read base image at offset {off}: {res}");
+                    return res;
+                }
+                // Without a base image, or beyond its end, unallocated chunks read as zeroes.
+                // SAFETY: see above.
+                unsafe {
+                    std::ptr::write_bytes(
+                        piece_addr.add(res as usize),
+                        0,
+                        (piece_len - res as u64) as usize,
+                    )
+                };
+                done += piece_len;
+                continue;
+            }
+            Err(e) => {
+                log::error!("{trace}: could not map offset {off}: {e}");
+                return -e.raw_os_error().unwrap_or(-EIO);
+            }
+        };
+
+        log::trace!("{trace}: {piece_len} bytes at {off} on target {target} at {target_off}");
+        let res = match (&backing.mem, &backing.mirror) {
+            (Some(mem), _) => {
+                // SAFETY: see above.
+                let buf = unsafe { std::slice::from_raw_parts_mut(piece_addr, bytes as usize) };
+                match op {
+                    libublk::sys::UBLK_IO_OP_READ => mem.read(target_off, buf) as i32,
+                    _ => mem.write(target_off, buf) as i32,
+                }
+            }
+            (None, Some(mirror)) => {
+                submit_mirrored(ctx, mirror, op, target_off, piece_addr, bytes).await
+            }
+            (None, None) => submit_target_io(ctx, op, target, target_off, piece_addr, bytes).await,
+        };
+        if res < 0 {
+            // Only media errors are recorded. Others, like EAGAIN once retries are exhausted or
+            // ENOSPC, don't say anything about the data at this offset.
+            if res == EIO || res == ENODATA {
+                if let Some(errors) = &backing.errors {
+                    errors.record(off, piece_len);
+                }
+            }
+            return res;
+        }
+        if op == libublk::sys::UBLK_IO_OP_WRITE {
+            if let Some(errors) = &backing.errors {
+                errors.clear(off, res as u64);
+            }
+        }
+
+        if encrypted && op == libublk::sys::UBLK_IO_OP_READ {
+            // Only full sectors can be decrypted.
+            let bytes = res as usize & !511;
+            let buf = unsafe { std::slice::from_raw_parts_mut(piece_addr, bytes) };
+            if let Err(e) = backing.enc.decrypt_area(buf, off >> 9) {
+                log::error!("{trace}: could not decrypt data: {e}");
+                return EIO;
+            }
+        }
+
+        done += res as u64;
+        // Short IO, the remaining pieces are not processed.
+        if (res as u64) < piece_len {
+            break;
+        }
+    }
+
+    // Data written in audit mode is only in the overlay.
+    if op == libublk::sys::UBLK_IO_OP_READ {
+        if let Some(overlay) = &backing.overlay {
+            let buf = unsafe { std::slice::from_raw_parts_mut(buf_addr, done as usize) };
+            overlay.apply(iod.start_sector, buf);
+        }
+    }
+
+    done as i32
 }
@@ -0,0 +1,206 @@
+use std::io;
+
+use crate::{
+    geometry::Geometry,
+    thin::{self, ThinMap},
+};
+
+/// Where a range of a device is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// Nothing is stored for the range, it reads as zeroes.
+    Unmapped,
+    /// The range was never written, and reads from the base image at the same offset.
+    Base,
+    /// The range is stored at these (target, offset) pairs. A mirrored device stores it on every
+    /// leg.
+    Targets(Vec<(usize, u64)>),
+}
+
+/// A range of a device, and where it is stored.
+#[derive(Debug)]
+pub struct Extent {
+    pub start: u64,
+    pub len: u64,
+    pub location: Location,
+}
+
+/// Resolve `len` bytes at `start` on a device to where they are stored. Adjacent pieces which
+/// are contiguous on the targets are merged in a single extent.
+pub fn resolve(
+    geometry: &Geometry,
+    thin: Option<&ThinMap>,
+    base: bool,
+    start: u64,
+    len: u64,
+) -> io::Result<Vec<Extent>> {
+    let mut extents: Vec<Extent> = Vec::new();
+    for (off, piece_len) in thin::split(start, len, geometry.split_size(thin.is_some())) {
+        let location = match thin.map(|thin| thin.lookup(off)).transpose()? {
+            Some(None) if base => Location::Base,
+            Some(None) => Location::Unmapped,
+            Some(Some(target_off)) => locate(geometry, target_off),
+            None => locate(geometry, off),
+        };
+        if let Some(last) = extents.last_mut() {
+            if last.start + last.len == off && continues(&last.location, last.len, &location) {
+                last.len += piece_len;
+                continue;
+            }
+        }
+        extents.push(Extent {
+            start: off,
+            len: piece_len,
+            location,
+        });
+    }
+
+    Ok(extents)
+}
+
+/// Location of an offset in the address space of the targets.
+fn locate(geometry: &Geometry, off: u64) -> Location {
+    match geometry {
+        Geometry::Mirrored { nr_targets, .. } => {
+            Location::Targets((0..*nr_targets).map(|leg| (leg, off)).collect())
+        }
+        _ => Location::Targets(vec![geometry.route(off)]),
+    }
+}
+
+/// Whether `next` directly follows an extent of `len` bytes stored at `location`.
+fn continues(location: &Location, len: u64, next: &Location) -> bool {
+    match (location, next) {
+        (Location::Targets(a), Location::Targets(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((a_target, a_off), (b_target, b_off))| {
+                        a_target == b_target && a_off + len == *b_off
+                    })
+        }
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::thin::{Reservation, CHUNK_SIZE};
+
+    fn summary(extents: &[Extent]) -> Vec<(u64, u64, Location)> {
+        extents
+            .iter()
+            .map(|extent| (extent.start, extent.len, extent.location.clone()))
+            .collect()
+    }
+
+    /// A thin map on an empty file which is removed once it is closed.
+    fn thin_map(name: &str) -> ThinMap {
+        let path = std::env::temp_dir().join(format!("vblock-map-{}-{name}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        ThinMap::open(&file, Some(8 * CHUNK_SIZE), None, None, false).unwrap()
+    }
+
+    fn allocate(map: &ThinMap, off: u64) {
+        let Reservation::New(chunk) = map.reserve(off).unwrap() else {
+            panic!("chunk at {off} is already allocated");
+        };
+        map.commit(chunk);
+    }
+
+    #[test]
+    fn resolve_single_in_one_extent() {
+        let extents = resolve(&Geometry::Single, None, false, 512, 10 * CHUNK_SIZE).unwrap();
+        assert_eq!(
+            summary(&extents),
+            vec![(512, 10 * CHUNK_SIZE, Location::Targets(vec![(0, 512)]))]
+        );
+    }
+
+    #[test]
+    fn resolve_linear_splits_at_targets() {
+        let geometry = Geometry::Linear(vec![2 * CHUNK_SIZE, 2 * CHUNK_SIZE]);
+        let extents = resolve(&geometry, None, false, CHUNK_SIZE, 2 * CHUNK_SIZE).unwrap();
+        assert_eq!(
+            summary(&extents),
+            vec![
+                (
+                    CHUNK_SIZE,
+                    CHUNK_SIZE,
+                    Location::Targets(vec![(0, CHUNK_SIZE)])
+                ),
+                (2 * CHUNK_SIZE, CHUNK_SIZE, Location::Targets(vec![(1, 0)])),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_striped_and_mirrored() {
+        let geometry = Geometry::Striped {
+            stripe_size: 4096,
+            nr_targets: 2,
+            target_size: CHUNK_SIZE,
+        };
+        let extents = resolve(&geometry, None, false, 0, 3 * 4096).unwrap();
+        assert_eq!(
+            summary(&extents),
+            vec![
+                (0, 4096, Location::Targets(vec![(0, 0)])),
+                (4096, 4096, Location::Targets(vec![(1, 0)])),
+                (8192, 4096, Location::Targets(vec![(0, 4096)])),
+            ]
+        );
+
+        let geometry = Geometry::Mirrored {
+            nr_targets: 2,
+            target_size: 4 * CHUNK_SIZE,
+        };
+        let extents = resolve(&geometry, None, false, 0, 3 * CHUNK_SIZE).unwrap();
+        assert_eq!(
+            summary(&extents),
+            vec![(0, 3 * CHUNK_SIZE, Location::Targets(vec![(0, 0), (1, 0)]))]
+        );
+    }
+
+    #[test]
+    fn resolve_thin() {
+        let map = thin_map("thin");
+        let data_start = map.data_start();
+        allocate(&map, CHUNK_SIZE);
+        allocate(&map, 2 * CHUNK_SIZE);
+        allocate(&map, 5 * CHUNK_SIZE);
+
+        let extents = resolve(&Geometry::Single, Some(&map), false, 0, 8 * CHUNK_SIZE).unwrap();
+        assert_eq!(
+            summary(&extents),
+            vec![
+                (0, CHUNK_SIZE, Location::Unmapped),
+                (
+                    CHUNK_SIZE,
+                    2 * CHUNK_SIZE,
+                    Location::Targets(vec![(0, data_start)])
+                ),
+                (3 * CHUNK_SIZE, 2 * CHUNK_SIZE, Location::Unmapped),
+                (
+                    5 * CHUNK_SIZE,
+                    CHUNK_SIZE,
+                    Location::Targets(vec![(0, data_start + 2 * CHUNK_SIZE)])
+                ),
+                (6 * CHUNK_SIZE, 2 * CHUNK_SIZE, Location::Unmapped),
+            ]
+        );
+
+        let extents = resolve(&Geometry::Single, Some(&map), true, 0, CHUNK_SIZE).unwrap();
+        assert_eq!(summary(&extents), vec![(0, CHUNK_SIZE, Location::Base)]);
+    }
+}
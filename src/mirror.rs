@@ -0,0 +1,286 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    ops::Range,
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::target::AlignedBuf;
+
+/// Directory holding a marker for every mirror leg which failed. It must survive a reboot, so a
+/// stale leg is never served after the mirror is assembled again.
+const STATE_DIR: &str = "/var/lib/vblock/failed-legs";
+
+/// Amount of bytes copied at once when resyncing a leg.
+const RESYNC_CHUNK_SIZE: usize = 1 << 20;
+
+/// Amount of read errors in a row after which a leg is failed.
+const READ_ERROR_LIMIT: u32 = 3;
+
+/// Health of the legs of a mirrored device.
+///
+/// A leg which misses a write, or returns [`READ_ERROR_LIMIT`] read errors in a row, is marked as
+/// failed, and is not used for reads or writes anymore, so it does not get served stale data.
+/// A single read error only moves the read to another leg. The failure is stored in
+/// [`STATE_DIR`] before the leg is dropped, and a leg with a stored failure starts as failed
+/// when the mirror is assembled again, until it is resynced from a healthy one.
+///
+/// Write mostly legs (e.g. a slow or remote replica) receive all writes, but are only read
+/// when no other healthy leg is left.
+///
+/// A read which fails on one leg but succeeds on another repairs the failing legs by writing
+/// the data read back to them. Writes to the range wait until the repair is done, so it never
+/// overwrites newer data.
+#[derive(Debug)]
+pub struct Mirror {
+    /// Canonical path of every leg.
+    legs: Vec<PathBuf>,
+    failed: Vec<AtomicBool>,
+    /// Read errors in a row of every leg.
+    read_errors: Vec<AtomicU32>,
+    write_mostly: Vec<bool>,
+    /// Leg to start the next read on, to spread reads over the legs.
+    next_read: AtomicUsize,
+    /// Ranges with a write or repair in progress.
+    busy: Mutex<Busy>,
+    /// Amount of reads repaired from another leg.
+    healed: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Busy {
+    next_id: u64,
+    /// (id, range, whether it is a repair) of every IO in progress.
+    ranges: Vec<(u64, Range<u64>, bool)>,
+}
+
+/// A write or repair in progress, which is done when this is dropped.
+#[derive(Debug)]
+pub struct RangeGuard<'a> {
+    mirror: &'a Mirror,
+    id: u64,
+}
+
+impl Mirror {
+    /// Create the state of a mirror, with the canonical path of every leg and whether it is
+    /// write mostly. Legs which failed before start as failed. If the stored failures can't be
+    /// read, or no healthy leg is left, the mirror can't be assembled.
+    pub fn new(legs: Vec<PathBuf>, write_mostly: Vec<bool>) -> io::Result<Mirror> {
+        let mut failed = Vec::with_capacity(legs.len());
+        for leg in &legs {
+            let stale = marker(leg).try_exists()?;
+            if stale {
+                log::warn!("mirror leg {} failed before, it is not used", leg.display());
+            }
+            failed.push(AtomicBool::new(stale));
+        }
+        let mirror = Mirror {
+            read_errors: legs.iter().map(|_| AtomicU32::new(0)).collect(),
+            legs,
+            failed,
+            write_mostly,
+            next_read: AtomicUsize::new(0),
+            busy: Default::default(),
+            healed: AtomicU64::new(0),
+        };
+        if mirror.healthy_legs().next().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "all legs of the mirror failed before",
+            ));
+        }
+        Ok(mirror)
+    }
+
+    /// Legs which have not failed.
+    pub fn healthy_legs(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.failed.len()).filter(|leg| !self.is_failed(*leg))
+    }
+
+    /// Healthy legs in the order a read should try them. Successive reads start on different
+    /// legs, write mostly legs are tried last.
+    pub fn read_order(&self) -> impl Iterator<Item = usize> + '_ {
+        let nr_legs = self.failed.len();
+        let first = self.next_read.fetch_add(1, Ordering::Relaxed) % nr_legs;
+        let legs = (0..nr_legs).map(move |i| (first + i) % nr_legs);
+        legs.clone()
+            .filter(|leg| !self.write_mostly[*leg])
+            .chain(legs.filter(|leg| self.write_mostly[*leg]))
+            .filter(|leg| !self.is_failed(*leg))
+    }
+
+    pub fn is_failed(&self, leg: usize) -> bool {
+        self.failed[leg].load(Ordering::Relaxed)
+    }
+
+    /// Canonical path of a leg.
+    pub fn leg(&self, leg: usize) -> &Path {
+        &self.legs[leg]
+    }
+
+    /// Record a successful read from a leg.
+    pub fn read_succeeded(&self, leg: usize) {
+        self.read_errors[leg].store(0, Ordering::Relaxed);
+    }
+
+    /// Record a read error of a leg. Returns true if the leg had too many read errors in a row,
+    /// and should be failed.
+    pub fn read_failed(&self, leg: usize) -> bool {
+        self.read_errors[leg].fetch_add(1, Ordering::Relaxed) + 1 >= READ_ERROR_LIMIT
+    }
+
+    /// Start a write of a range. Returns None while an overlapping range is being repaired, the
+    /// write must wait until that is done.
+    pub fn start_write(&self, range: Range<u64>) -> Option<RangeGuard<'_>> {
+        self.start(range, false)
+    }
+
+    /// Start the repair of a range, before reading the data to repair it with. Returns None if
+    /// an overlapping write or repair is in progress, which could change the data read.
+    pub fn start_repair(&self, range: Range<u64>) -> Option<RangeGuard<'_>> {
+        self.start(range, true)
+    }
+
+    fn start(&self, range: Range<u64>, repair: bool) -> Option<RangeGuard<'_>> {
+        let mut busy = self.busy.lock().unwrap();
+        let conflict = busy.ranges.iter().any(|(_, busy, busy_repair)| {
+            (repair || *busy_repair) && busy.start < range.end && range.start < busy.end
+        });
+        if conflict {
+            return None;
+        }
+        let id = busy.next_id;
+        busy.next_id += 1;
+        busy.ranges.push((id, range, repair));
+        Some(RangeGuard { mirror: self, id })
+    }
+
+    /// Record a read repaired from another leg. Returns the amount of repaired reads so far.
+    pub fn healed(&self) -> u64 {
+        self.healed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Mark a leg as failed after it returned error code res. Its failure must be stored with
+    /// [`store_marker`] first, as the leg would otherwise be used with stale data once the
+    /// mirror is assembled again.
+    pub fn mark_failed(&self, leg: usize, res: i32) {
+        if self.failed[leg].swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let healthy = self.healthy_legs().count();
+        log::error!(
+            "mirror leg {leg} failed with error {res}, {healthy} of {} legs left",
+            self.failed.len()
+        );
+    }
+
+    /// Copy `size` bytes from a healthy leg to every failed one, and use them again. The files
+    /// are the opened legs, in the order of the legs of the mirror. This must be done before the
+    /// device serves IO.
+    pub fn resync(&self, files: &[&File], size: u64) -> io::Result<()> {
+        let Some(src) = self.read_order().next() else {
+            return Ok(());
+        };
+        let mut buf = AlignedBuf::zeroed(RESYNC_CHUNK_SIZE);
+        for leg in (0..self.legs.len()).filter(|leg| self.is_failed(*leg)) {
+            log::warn!(
+                "resyncing mirror leg {} from {}",
+                self.legs[leg].display(),
+                self.legs[src].display()
+            );
+            for off in (0..size).step_by(RESYNC_CHUNK_SIZE) {
+                let len = (size - off).min(RESYNC_CHUNK_SIZE as u64) as usize;
+                files[src].read_exact_at(&mut buf[..len], off)?;
+                files[leg].write_all_at(&buf[..len], off)?;
+            }
+            files[leg].sync_all()?;
+            fs::remove_file(marker(&self.legs[leg]))?;
+            self.failed[leg].store(false, Ordering::Relaxed);
+            self.read_errors[leg].store(0, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RangeGuard<'_> {
+    fn drop(&mut self) {
+        let mut busy = self.mirror.busy.lock().unwrap();
+        busy.ranges.retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+/// Path of the marker of a failed leg.
+fn marker(leg: &Path) -> PathBuf {
+    Path::new(STATE_DIR).join(leg.display().to_string().replace('/', "!"))
+}
+
+/// Durably store the marker of a failed leg. This blocks until the marker is synced.
+pub fn store_marker(leg: &Path) -> io::Result<()> {
+    fs::create_dir_all(STATE_DIR)?;
+    let mut file = File::create(marker(leg))?;
+    file.write_all(leg.display().to_string().as_bytes())?;
+    file.sync_all()?;
+    File::open(STATE_DIR)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(write_mostly: Vec<bool>) -> Mirror {
+        let legs = (0..write_mostly.len())
+            .map(|leg| PathBuf::from(format!("/nonexistent/vblock-mirror-{leg}")))
+            .collect();
+        Mirror::new(legs, write_mostly).unwrap()
+    }
+
+    #[test]
+    fn read_errors_in_a_row_fail_a_leg() {
+        let mirror = mirror(vec![false, false]);
+        for _ in 1..READ_ERROR_LIMIT {
+            assert!(!mirror.read_failed(0));
+        }
+        mirror.read_succeeded(0);
+        for _ in 1..READ_ERROR_LIMIT {
+            assert!(!mirror.read_failed(0));
+        }
+        assert!(mirror.read_failed(0));
+        assert!(!mirror.read_failed(1));
+    }
+
+    #[test]
+    fn failed_legs_are_not_read() {
+        let mirror = mirror(vec![true, false, false]);
+        // Write mostly legs are read last.
+        for _ in 0..3 {
+            assert_eq!(mirror.read_order().last(), Some(0));
+        }
+        mirror.mark_failed(1, -(nix::Error::EIO as i32));
+        assert_eq!(mirror.healthy_legs().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(mirror.read_order().collect::<Vec<_>>(), vec![2, 0]);
+        mirror.mark_failed(2, -(nix::Error::EIO as i32));
+        assert_eq!(mirror.read_order().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn writes_wait_for_repairs() {
+        let mirror = mirror(vec![false, false]);
+        let write = mirror.start_write(0..4096).unwrap();
+        // Writes may overlap each other, but not a repair.
+        assert!(mirror.start_write(0..8192).is_some());
+        assert!(mirror.start_repair(0..512).is_none());
+        let repair = mirror.start_repair(4096..8192).unwrap();
+        assert!(mirror.start_write(8191..8192).is_none());
+        assert!(mirror.start_repair(4096..4608).is_none());
+        drop(write);
+        drop(repair);
+        assert!(mirror.start_repair(0..8192).is_some());
+        assert_eq!(mirror.healed(), 1);
+        assert_eq!(mirror.healed(), 2);
+    }
+}
@@ -0,0 +1,41 @@
+use std::{collections::HashMap, sync::Mutex};
+
+/// Granularity at which data is tracked in the overlay.
+const SECTOR_SIZE: usize = 512;
+
+/// An in-memory overlay holding all data written to a device whose target must not be modified.
+///
+/// Data is tracked per sector. Sectors which were never written are served from the target.
+/// Nothing is persisted, all writes are lost once the device is removed.
+#[derive(Debug, Default)]
+pub struct Overlay {
+    sectors: Mutex<HashMap<u64, Box<[u8; SECTOR_SIZE]>>>,
+}
+
+impl Overlay {
+    /// Store data written at the given sector. The length of data must be a multiple of the
+    /// sector size.
+    pub fn write(&self, start_sector: u64, data: &[u8]) {
+        let mut sectors = self.sectors.lock().unwrap();
+        for (sector, chunk) in (start_sector..).zip(data.chunks_exact(SECTOR_SIZE)) {
+            sectors
+                .entry(sector)
+                .or_insert_with(|| Box::new([0; SECTOR_SIZE]))
+                .copy_from_slice(chunk);
+        }
+    }
+
+    /// Replace the sectors in buf, which was read from the target at the given sector, with the
+    /// ones which were written to the overlay.
+    pub fn apply(&self, start_sector: u64, buf: &mut [u8]) {
+        let sectors = self.sectors.lock().unwrap();
+        if sectors.is_empty() {
+            return;
+        }
+        for (sector, chunk) in (start_sector..).zip(buf.chunks_exact_mut(SECTOR_SIZE)) {
+            if let Some(data) = sectors.get(&sector) {
+                chunk.copy_from_slice(&data[..]);
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::prelude::FileTypeExt,
+    path::Path,
+};
+
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+/// Path of the ublk control device.
+const UBLK_CONTROL_PATH: &str = "/dev/ublk-control";
+
+/// Bit of CAP_SYS_ADMIN in the capability sets, defined in linux/capability.h
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Size of a submission queue entry.
+const SQE_SIZE: u64 = 64;
+/// Size of a completion queue entry. The completion queue has twice the entries of the
+/// submission queue.
+const CQE_SIZE: u64 = 16;
+/// Page size used to round up ring allocations.
+const PAGE_SIZE: u64 = 4096;
+/// First kernel version which charges io_uring rings to the memory cgroup instead of the locked
+/// memory limit.
+const MEMCG_ACCOUNTING_VERSION: (u32, u32) = (5, 12);
+
+/// A requirement for managing ublk devices which is not met in the current environment.
+#[derive(Debug, Clone)]
+pub enum PrivilegeError {
+    /// CAP_SYS_ADMIN is not in the effective capability set of the process.
+    MissingCapability,
+    /// The ublk control device does not exist.
+    ControlDeviceMissing,
+    /// The ublk control device exists, but is not a character device.
+    ControlDeviceInvalid,
+    /// The ublk control device exists, but can't be opened.
+    ControlDeviceInaccessible(io::ErrorKind),
+    /// /dev is not a devtmpfs mount, so device nodes for new devices won't appear.
+    StaticDevFilesystem(String),
+    /// The locked memory limit is lower than what the io_uring rings need, on a kernel which
+    /// charges them to it.
+    MemlockLimit {
+        /// The current soft limit.
+        current: u64,
+        /// The amount of memory which needs to be locked.
+        required: u64,
+    },
+    /// IO error while querying the environment.
+    IOError(io::ErrorKind),
+    /// Error while querying resource limits.
+    QueryError(nix::Error),
+}
+
+/// Verify that the current process is able to create and serve ublk devices. All unmet
+/// requirements are returned, not just the first one.
+pub fn check() -> Vec<PrivilegeError> {
+    let mut errors = Vec::new();
+
+    match has_capability(CAP_SYS_ADMIN) {
+        Ok(true) => {}
+        Ok(false) => errors.push(PrivilegeError::MissingCapability),
+        Err(e) => errors.push(e),
+    }
+
+    if let Err(e) = check_control_device() {
+        errors.push(e);
+    }
+
+    if let Err(e) = check_dev_filesystem() {
+        errors.push(e);
+    }
+
+    errors
+}
+
+/// Check requirements which only some kernels have for devices with the given configuration. As
+/// the kernel version does not tell everything (e.g. backported changes), these are not fatal.
+pub fn warnings(nr_queues: u32, depth: u32) -> Vec<PrivilegeError> {
+    let mut warnings = Vec::new();
+
+    match memlock_charged() {
+        Ok(true) => {
+            if let Err(e) = check_memlock(nr_queues, depth) {
+                warnings.push(e);
+            }
+        }
+        Ok(false) => {}
+        Err(e) => warnings.push(e),
+    }
+
+    warnings
+}
+
+/// Whether the running kernel charges io_uring rings to the locked memory limit.
+fn memlock_charged() -> Result<bool, PrivilegeError> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease")?;
+    // e.g. 6.1.0-13-amd64
+    let mut version = release.trim().split(['.', '-']).map(str::parse::<u32>);
+    match (version.next(), version.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Ok((major, minor) < MEMCG_ACCOUNTING_VERSION),
+        _ => Err(PrivilegeError::IOError(io::ErrorKind::InvalidData)),
+    }
+}
+
+/// Amount of memory which needs to be locked for the io_uring rings of a device with the given
+/// amount of queues and queue depth.
+pub fn required_memlock(nr_queues: u32, depth: u32) -> u64 {
+    let ring = depth as u64 * (SQE_SIZE + 2 * CQE_SIZE + 4);
+    nr_queues as u64 * ring.next_multiple_of(PAGE_SIZE)
+}
+
+/// Raise the memlock limit so the io_uring rings of a device with the given amount of queues and
+/// queue depth can be created. The soft limit is raised up to the hard limit first. If that is not
+/// sufficient, raising the hard limit is attempted as well, which requires CAP_SYS_RESOURCE.
+pub fn raise_memlock(nr_queues: u32, depth: u32) -> Result<(), PrivilegeError> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_MEMLOCK)?;
+    let required = required_memlock(nr_queues, depth);
+    if soft >= required {
+        return Ok(());
+    }
+
+    // RLIM_INFINITY is u64::MAX, so no special handling is needed for it.
+    let res = if hard >= required {
+        setrlimit(Resource::RLIMIT_MEMLOCK, required, hard)
+    } else {
+        setrlimit(Resource::RLIMIT_MEMLOCK, required, required)
+    };
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(nix::Error::EPERM) => Err(PrivilegeError::MemlockLimit {
+            current: soft,
+            required,
+        }),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check if a capability is in the effective set of the current process.
+fn has_capability(cap: u32) -> Result<bool, PrivilegeError> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let cap_eff = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .ok_or(PrivilegeError::IOError(io::ErrorKind::InvalidData))?;
+
+    Ok(cap_eff & (1 << cap) != 0)
+}
+
+/// Check if the ublk control device is present and accessible.
+fn check_control_device() -> Result<(), PrivilegeError> {
+    let meta = match fs::metadata(UBLK_CONTROL_PATH) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(PrivilegeError::ControlDeviceMissing)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !meta.file_type().is_char_device() {
+        return Err(PrivilegeError::ControlDeviceInvalid);
+    }
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(UBLK_CONTROL_PATH)
+        .map_err(|e| PrivilegeError::ControlDeviceInaccessible(e.kind()))?;
+
+    Ok(())
+}
+
+/// Check that /dev is managed by the kernel, so /dev/ublkcN and /dev/ublkbN show up once a device
+/// is added.
+fn check_dev_filesystem() -> Result<(), PrivilegeError> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    // Fields are separated by spaces, the mount point is the 5th field and the filesystem type
+    // is the first field after the "-" separator. Later mounts shadow earlier ones.
+    let fs_type = mountinfo
+        .lines()
+        .filter(|line| line.split(' ').nth(4).map(Path::new) == Some(Path::new("/dev")))
+        .filter_map(|line| line.split(" - ").nth(1)?.split(' ').next())
+        .next_back();
+
+    match fs_type {
+        Some("devtmpfs") => Ok(()),
+        Some(fs_type) => Err(PrivilegeError::StaticDevFilesystem(fs_type.to_string())),
+        // /dev is part of the root filesystem, which is never managed by the kernel.
+        None => Err(PrivilegeError::StaticDevFilesystem("rootfs".to_string())),
+    }
+}
+
+/// Check if the memlock limit is sufficient for the io_uring rings of a device.
+fn check_memlock(nr_queues: u32, depth: u32) -> Result<(), PrivilegeError> {
+    let (soft, _) = getrlimit(Resource::RLIMIT_MEMLOCK)?;
+    let required = required_memlock(nr_queues, depth);
+    if soft < required {
+        return Err(PrivilegeError::MemlockLimit {
+            current: soft,
+            required,
+        });
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for PrivilegeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrivilegeError::MissingCapability => f.write_str(
+                "CAP_SYS_ADMIN is not in the effective capability set, run as root or add the capability to the container",
+            ),
+            PrivilegeError::ControlDeviceMissing => f.write_fmt(format_args!(
+                "{UBLK_CONTROL_PATH} does not exist, load the ublk_drv module on the host and pass the device into the container"
+            )),
+            PrivilegeError::ControlDeviceInvalid => f.write_fmt(format_args!(
+                "{UBLK_CONTROL_PATH} is not a character device"
+            )),
+            PrivilegeError::ControlDeviceInaccessible(kind) => f.write_fmt(format_args!(
+                "can't open {UBLK_CONTROL_PATH}: {kind}, check the device cgroup rules of the container"
+            )),
+            PrivilegeError::StaticDevFilesystem(fs_type) => f.write_fmt(format_args!(
+                "/dev is a {fs_type} mount instead of devtmpfs, device nodes of new ublk devices won't appear"
+            )),
+            PrivilegeError::MemlockLimit { current, required } => f.write_fmt(format_args!(
+                "locked memory limit is {current} bytes but {required} bytes are required and it can't be raised, set it with `ulimit -l {}`, `LimitMEMLOCK=` in the systemd unit or the memlock ulimit of the container",
+                required.div_ceil(1024)
+            )),
+            PrivilegeError::IOError(kind) => f.write_fmt(format_args!(
+                "i/o error {kind} while checking privileges"
+            )),
+            PrivilegeError::QueryError(e) => f.write_fmt(format_args!(
+                "error {} while querying resource limits",
+                e.desc()
+            )),
+        }
+    }
+}
+
+impl std::error::Error for PrivilegeError {}
+
+impl From<io::Error> for PrivilegeError {
+    fn from(value: io::Error) -> Self {
+        PrivilegeError::IOError(value.kind())
+    }
+}
+
+impl From<nix::Error> for PrivilegeError {
+    fn from(value: nix::Error) -> Self {
+        PrivilegeError::QueryError(value)
+    }
+}
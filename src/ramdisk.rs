@@ -0,0 +1,141 @@
+use std::{ffi::c_void, io, num::NonZeroUsize, os::fd::BorrowedFd, path::PathBuf};
+
+use nix::sys::mman::{madvise, mmap, munmap, MapFlags, MmapAdvise, ProtFlags};
+
+/// Target name selecting a ramdisk instead of a file.
+pub const TARGET: &str = "mem";
+
+/// Size of a page, the granularity at which memory is returned to the system on discard.
+const PAGE_SIZE: u64 = 4096;
+
+/// Whether the targets of a device select a ramdisk.
+pub fn is_ramdisk(targets: &[PathBuf]) -> bool {
+    matches!(targets, [target] if target.as_os_str() == TARGET)
+}
+
+/// A device backed by anonymous memory.
+///
+/// Memory is only allocated when it is first written, and all data is lost when the device is
+/// removed. The data is not encrypted, so fresh and discarded memory reads as zeroes. Nothing is
+/// registered with the queue rings, IO is served by copying from and to the memory directly.
+#[derive(Debug)]
+pub struct Ramdisk {
+    addr: *mut u8,
+    size: u64,
+}
+
+// SAFETY: the mapping is only accessed through the raw pointer, concurrent IO to the same range
+// behaves like it does on any other block device: the result is one of the written buffers, or a
+// mix of them.
+unsafe impl Send for Ramdisk {}
+unsafe impl Sync for Ramdisk {}
+
+impl Ramdisk {
+    /// Map a ramdisk of size bytes.
+    pub fn new(size: u64) -> io::Result<Ramdisk> {
+        let len = NonZeroUsize::new(size as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ramdisk size is 0"))?;
+        // SAFETY: an anonymous mapping doesn't alias any existing memory.
+        let addr = unsafe {
+            mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
+                None::<BorrowedFd>,
+                0,
+            )?
+        };
+
+        Ok(Ramdisk {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+
+    /// Copy data at off into buf. Returns the amount of bytes read, which is less than the size
+    /// of buf at the end of the ramdisk.
+    pub fn read(&self, off: u64, buf: &mut [u8]) -> usize {
+        let len = self.clamp(off, buf.len());
+        if len == 0 {
+            return 0;
+        }
+        // SAFETY: off..off + len is within the mapping, and buf doesn't overlap it.
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.addr.add(off as usize), buf.as_mut_ptr(), len)
+        };
+        len
+    }
+
+    /// Copy buf to off. Returns the amount of bytes written, which is less than the size of buf
+    /// at the end of the ramdisk.
+    pub fn write(&self, off: u64, buf: &[u8]) -> usize {
+        let len = self.clamp(off, buf.len());
+        if len == 0 {
+            return 0;
+        }
+        // SAFETY: see read.
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), self.addr.add(off as usize), len) };
+        len
+    }
+
+    /// Zero len bytes at off. Whole pages are returned to the system.
+    pub fn discard(&self, off: u64, len: u64) {
+        let len = self.clamp(off, len as usize) as u64;
+        if len == 0 {
+            return;
+        }
+        let end = off + len;
+        let page_start = off.next_multiple_of(PAGE_SIZE);
+        let page_end = end - end % PAGE_SIZE;
+        if page_start >= page_end {
+            // SAFETY: off..end is within the mapping.
+            unsafe { std::ptr::write_bytes(self.addr.add(off as usize), 0, len as usize) };
+            return;
+        }
+
+        // SAFETY: the head and tail are within the mapping.
+        unsafe {
+            std::ptr::write_bytes(self.addr.add(off as usize), 0, (page_start - off) as usize);
+            std::ptr::write_bytes(
+                self.addr.add(page_end as usize),
+                0,
+                (end - page_end) as usize,
+            );
+        }
+        // SAFETY: the range is page aligned and within the mapping. Private anonymous pages read
+        // as zeroes after this.
+        let res = unsafe {
+            madvise(
+                self.addr.add(page_start as usize) as *mut c_void,
+                (page_end - page_start) as usize,
+                MmapAdvise::MADV_DONTNEED,
+            )
+        };
+        if let Err(e) = res {
+            log::warn!("could not release ramdisk pages, zeroing them instead: {e}");
+            // SAFETY: see above.
+            unsafe {
+                std::ptr::write_bytes(
+                    self.addr.add(page_start as usize),
+                    0,
+                    (page_end - page_start) as usize,
+                )
+            };
+        }
+    }
+
+    /// Amount of bytes of a range of len bytes at off which is within the ramdisk.
+    fn clamp(&self, off: u64, len: usize) -> usize {
+        self.size.saturating_sub(off).min(len as u64) as usize
+    }
+}
+
+impl Drop for Ramdisk {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is not used anymore.
+        if let Err(e) = unsafe { munmap(self.addr as *mut c_void, self.size as usize) } {
+            log::warn!("could not unmap ramdisk: {e}");
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{collections::HashSet, ops::Range, sync::Mutex};
+
+/// Locks on target blocks, which serialize writes to a target whose logical block size is larger
+/// than the one of the device.
+///
+/// Such a write is done as a read-modify-write of the target blocks it touches. Without locking,
+/// two writes to different sectors in the same target block would both read the old block, and
+/// the second write back would undo the first one. All writes to the target take the lock, also
+/// the aligned ones, as those would otherwise race with a read-modify-write in the same way.
+#[derive(Debug, Default)]
+pub struct BlockLocks {
+    /// Locked (target, block) pairs.
+    locked: Mutex<HashSet<(usize, u64)>>,
+}
+
+/// Target blocks touched by an IO on a target with larger blocks than the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Range of the touched blocks on the target, in bytes.
+    pub range: Range<u64>,
+    /// Offset of the IO in the first block.
+    pub head: usize,
+    /// Size of the IO.
+    pub len: usize,
+    block: u64,
+}
+
+/// Blocks locked by a write, they are unlocked when this is dropped.
+#[derive(Debug)]
+pub struct BlockGuard<'a> {
+    locks: &'a BlockLocks,
+    target: usize,
+    blocks: Range<u64>,
+}
+
+impl BlockLocks {
+    /// Lock a range of blocks on a target. Returns None if any of them is locked already, in
+    /// which case none of them is locked.
+    pub fn try_lock(&self, target: usize, blocks: Range<u64>) -> Option<BlockGuard<'_>> {
+        let mut locked = self.locked.lock().unwrap();
+        if blocks
+            .clone()
+            .any(|block| locked.contains(&(target, block)))
+        {
+            return None;
+        }
+        locked.extend(blocks.clone().map(|block| (target, block)));
+
+        Some(BlockGuard {
+            locks: self,
+            target,
+            blocks,
+        })
+    }
+}
+
+impl Span {
+    /// Blocks of `block` bytes touched by an IO of `len` bytes at `off`.
+    pub fn new(off: u64, len: u32, block: u64) -> Span {
+        let start = off - off % block;
+        let end = (off + len as u64).next_multiple_of(block);
+        Span {
+            range: start..end,
+            head: (off - start) as usize,
+            len: len as usize,
+            block,
+        }
+    }
+
+    /// Whether the IO covers whole blocks, so it needs no read-modify-write.
+    pub fn is_aligned(&self) -> bool {
+        self.head == 0 && self.range.end - self.range.start == self.len as u64
+    }
+
+    /// Indices of the touched blocks.
+    pub fn blocks(&self) -> Range<u64> {
+        self.range.start / self.block..self.range.end / self.block
+    }
+
+    /// Amount of bytes of the IO done, if an IO of the whole span transferred `res` bytes.
+    pub fn done(&self, res: usize) -> usize {
+        res.saturating_sub(self.head).min(self.len)
+    }
+}
+
+impl Drop for BlockGuard<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.locks.locked.lock().unwrap();
+        for block in self.blocks.clone() {
+            locked.remove(&(self.target, block));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn span_inside_a_block() {
+        let span = Span::new(4096 + 512, 1024, 4096);
+        assert_eq!(span.range, 4096..8192);
+        assert_eq!(span.head, 512);
+        assert!(!span.is_aligned());
+        assert_eq!(span.blocks(), 1..2);
+    }
+
+    #[test]
+    fn span_with_head_and_tail() {
+        let span = Span::new(4096 - 512, 1024, 4096);
+        assert_eq!(span.range, 0..8192);
+        assert_eq!(span.head, 3584);
+        assert!(!span.is_aligned());
+        assert_eq!(span.blocks(), 0..2);
+
+        // Only a tail.
+        let span = Span::new(8192, 4096 + 512, 4096);
+        assert_eq!(span.range, 8192..16384);
+        assert_eq!(span.head, 0);
+        assert!(!span.is_aligned());
+    }
+
+    #[test]
+    fn span_of_whole_blocks() {
+        let span = Span::new(8192, 8192, 4096);
+        assert_eq!(span.range, 8192..16384);
+        assert!(span.is_aligned());
+        assert_eq!(span.blocks(), 2..4);
+    }
+
+    #[test]
+    fn span_done() {
+        let span = Span::new(4096 + 512, 1024, 4096);
+        assert_eq!(span.done(4096), 1024);
+        assert_eq!(span.done(1000), 488);
+        assert_eq!(span.done(100), 0);
+    }
+
+    #[test]
+    fn lock_blocks() {
+        let locks = BlockLocks::default();
+        let guard = locks.try_lock(0, 0..2).unwrap();
+        assert!(locks.try_lock(0, 1..3).is_none());
+        // Other targets are independent.
+        let other = locks.try_lock(1, 1..3).unwrap();
+        // A failed lock doesn't lock part of the range.
+        assert!(locks.try_lock(0, 2..3).is_some());
+        drop(guard);
+        assert!(locks.try_lock(0, 1..3).is_some());
+        drop(other);
+        assert!(locks.try_lock(1, 0..4).is_some());
+    }
+}
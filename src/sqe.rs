@@ -0,0 +1,178 @@
+use std::{cell::RefCell, fmt};
+
+use io_uring::{squeue, types, IoUring};
+
+/// Size of a sector, all IO to the target is done in multiples of this.
+const SECTOR_SIZE: u64 = 512;
+
+/// An IO which can't be submitted to the target. This only fails the request the IO belongs
+/// to.
+#[derive(Debug, Clone)]
+pub enum SqeError {
+    /// The buffer of the IO is not within the IO buffer of its tag.
+    BufferOutOfBounds {
+        /// Offset of the buffer in the IO buffer of the tag.
+        offset: u64,
+        /// Length of the buffer.
+        len: u64,
+    },
+    /// The length of the IO is not a multiple of the sector size.
+    UnalignedLength(u64),
+    /// The IO extends beyond the maximum file offset.
+    OffsetOverflow(u64),
+    /// The target file is not registered in the fixed file table.
+    InvalidFd(u32),
+    /// The submission queue is full, even after submitting all pending entries.
+    QueueFull,
+}
+
+impl SqeError {
+    /// The error code to complete the request with.
+    pub fn errno(&self) -> i32 {
+        match self {
+            // The queue drains as IOs complete, so the request can be retried.
+            SqeError::QueueFull => -(nix::Error::EAGAIN as i32),
+            _ => -(nix::Error::EINVAL as i32),
+        }
+    }
+}
+
+/// Validate a buffer at `offset` bytes in an IO buffer of `buf_size` bytes, returning the length
+/// as used in an SQE.
+pub fn check_buf(offset: u64, len: u64, buf_size: u32) -> Result<u32, SqeError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(SqeError::UnalignedLength(len));
+    }
+    match offset.checked_add(len) {
+        Some(end) if end <= buf_size as u64 => Ok(len as u32),
+        _ => Err(SqeError::BufferOutOfBounds { offset, len }),
+    }
+}
+
+/// Validate an IO at `off` on the target, returning the offset as used in an SQE.
+pub fn check_offset(off: u64, len: u64) -> Result<u64, SqeError> {
+    match off.checked_add(len) {
+        Some(end) if end <= i64::MAX as u64 => Ok(off),
+        _ => Err(SqeError::OffsetOverflow(off)),
+    }
+}
+
+/// Validate a fixed file index, in a table holding the ublk character device at index 0,
+/// followed by `nr_files` target files.
+pub fn check_fd(index: u32, nr_files: usize) -> Result<types::Fixed, SqeError> {
+    if index == 0 || index as usize > nr_files {
+        return Err(SqeError::InvalidFd(index));
+    }
+    Ok(types::Fixed(index))
+}
+
+/// Push an entry to the submission queue. If the queue is full, the pending entries are
+/// submitted first to make room.
+///
+/// # Safety
+///
+/// All buffers referenced by the entry must stay valid until it completes.
+pub unsafe fn push(ring: &RefCell<IoUring>, sqe: &squeue::Entry) -> Result<(), SqeError> {
+    let mut ring = ring.borrow_mut();
+    if ring.submission().push(sqe).is_ok() {
+        return Ok(());
+    }
+
+    if let Err(e) = ring.submit() {
+        log::warn!("could not submit pending entries: {e}");
+        return Err(SqeError::QueueFull);
+    }
+    if ring.submission().push(sqe).is_err() {
+        return Err(SqeError::QueueFull);
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for SqeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SqeError::BufferOutOfBounds { offset, len } => f.write_fmt(format_args!(
+                "buffer of {len} bytes at offset {offset} exceeds the IO buffer"
+            )),
+            SqeError::UnalignedLength(len) => f.write_fmt(format_args!(
+                "length {len} is not a multiple of the sector size"
+            )),
+            SqeError::OffsetOverflow(off) => f.write_fmt(format_args!(
+                "IO at offset {off} exceeds the maximum offset"
+            )),
+            SqeError::InvalidFd(index) => {
+                f.write_fmt(format_args!("fixed file {index} is not registered"))
+            }
+            SqeError::QueueFull => f.write_str("submission queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for SqeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_within_io_buffer() {
+        assert_eq!(check_buf(0, 4096, 4096).unwrap(), 4096);
+        assert_eq!(check_buf(512, 3584, 4096).unwrap(), 3584);
+        assert!(matches!(
+            check_buf(512, 4096, 4096),
+            Err(SqeError::BufferOutOfBounds {
+                offset: 512,
+                len: 4096
+            })
+        ));
+        assert!(matches!(
+            check_buf(u64::MAX - 511, 512, 4096),
+            Err(SqeError::BufferOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn unaligned_length() {
+        assert!(matches!(
+            check_buf(0, 100, 4096),
+            Err(SqeError::UnalignedLength(100))
+        ));
+        assert!(matches!(
+            check_buf(0, 4097, 8192),
+            Err(SqeError::UnalignedLength(4097))
+        ));
+    }
+
+    #[test]
+    fn offset_overflow() {
+        assert_eq!(check_offset(1 << 40, 4096).unwrap(), 1 << 40);
+        assert_eq!(
+            check_offset(i64::MAX as u64 - 4096, 4096).unwrap(),
+            i64::MAX as u64 - 4096
+        );
+        assert!(matches!(
+            check_offset(i64::MAX as u64, 512),
+            Err(SqeError::OffsetOverflow(_))
+        ));
+        assert!(matches!(
+            check_offset(u64::MAX, 512),
+            Err(SqeError::OffsetOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn fixed_file_in_table() {
+        assert!(matches!(check_fd(1, 1), Ok(types::Fixed(1))));
+        assert!(matches!(check_fd(3, 3), Ok(types::Fixed(3))));
+        // Index 0 is the ublk character device.
+        assert!(matches!(check_fd(0, 3), Err(SqeError::InvalidFd(0))));
+        assert!(matches!(check_fd(4, 3), Err(SqeError::InvalidFd(4))));
+    }
+
+    #[test]
+    fn errno_of_errors() {
+        assert_eq!(SqeError::QueueFull.errno(), -(nix::Error::EAGAIN as i32));
+        assert_eq!(SqeError::InvalidFd(0).errno(), -(nix::Error::EINVAL as i32));
+    }
+}
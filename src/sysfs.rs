@@ -0,0 +1,67 @@
+use std::{fs, io, path::PathBuf};
+
+use nix::sys::stat::{major, minor};
+
+/// Tunables of the request queue of a block device, applied through sysfs.
+///
+/// The kernel defaults are chosen for physical devices, and are often not appropriate for ublk
+/// devices, where the IO is handled (and possibly scheduled) by a userspace target anyway. Every
+/// setting is optional, unset settings are left at the kernel default.
+#[derive(Debug, Clone, Default)]
+pub struct QueueSettings {
+    /// IO scheduler of the queue, e.g. `none` or `mq-deadline`.
+    pub scheduler: Option<String>,
+    /// Maximum amount of requests allocated in the block layer.
+    pub nr_requests: Option<u32>,
+    /// Completion CPU affinity: 0 to complete on any CPU, 1 to complete in the submitting CPU's
+    /// group, 2 to force completion on the submitting CPU.
+    pub rq_affinity: Option<u32>,
+    /// Target latency of writeback throttling in microseconds, 0 disables it.
+    pub wbt_lat_usec: Option<u64>,
+}
+
+impl QueueSettings {
+    /// Apply the settings to the request queue of the given block device (e.g. `ublkb0`).
+    ///
+    /// Every set value is attempted, even if an earlier one fails. The first error is returned.
+    pub fn apply(&self, disk: &str) -> io::Result<()> {
+        let queue = PathBuf::from("/sys/block").join(disk).join("queue");
+
+        let mut res = Ok(());
+        let mut write = |attr: &str, value: String| {
+            if let Err(e) = fs::write(queue.join(attr), value) {
+                log::warn!("could not set {attr} of {disk}: {e}");
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        };
+
+        if let Some(scheduler) = &self.scheduler {
+            write("scheduler", scheduler.clone());
+        }
+        if let Some(nr_requests) = self.nr_requests {
+            write("nr_requests", nr_requests.to_string());
+        }
+        if let Some(rq_affinity) = self.rq_affinity {
+            write("rq_affinity", rq_affinity.to_string());
+        }
+        if let Some(wbt_lat_usec) = self.wbt_lat_usec {
+            write("wbt_lat_usec", wbt_lat_usec.to_string());
+        }
+
+        res
+    }
+}
+
+/// Whether the block device with the given device number has a volatile write cache, according
+/// to its `queue/write_cache`.
+pub fn has_write_cache(rdev: u64) -> io::Result<bool> {
+    let mut dev = fs::canonicalize(format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev)))?;
+    // The queue of a partition is the one of the whole disk.
+    if dev.join("partition").exists() {
+        dev.pop();
+    }
+    let mode = fs::read_to_string(dev.join("queue").join("write_cache"))?;
+    Ok(mode.trim() != "write through")
+}
@@ -0,0 +1,124 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    ops::{Deref, DerefMut},
+    os::unix::prelude::{FileExt, OpenOptionsExt},
+    path::Path,
+};
+
+use nix::fcntl::OFlag;
+
+/// Size and alignment of blocks in an [`AlignedBuf`]. This is sufficient for direct IO with all
+/// common logical block sizes.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// An opened backing target.
+#[derive(Debug)]
+pub struct Target {
+    /// The opened file.
+    pub file: File,
+    /// Whether the file is opened for direct IO. If this is false, writes end up in the page cache
+    /// and flushes need to sync the file to be durable.
+    pub direct: bool,
+}
+
+/// A zeroed buffer in memory aligned for direct IO.
+pub struct AlignedBuf {
+    blocks: Vec<AlignedBlock>,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; BLOCK_SIZE]);
+
+impl AlignedBuf {
+    /// Allocate a buffer of len bytes, rounded up to a multiple of [`BLOCK_SIZE`].
+    pub fn zeroed(len: usize) -> AlignedBuf {
+        AlignedBuf {
+            blocks: vec![AlignedBlock([0; BLOCK_SIZE]); len.div_ceil(BLOCK_SIZE)],
+        }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: AlignedBlock is a plain byte array without padding, so the blocks form a
+        // contiguous byte buffer.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.blocks.as_ptr() as *const u8,
+                self.blocks.len() * BLOCK_SIZE,
+            )
+        }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: see deref.
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.blocks.as_mut_ptr() as *mut u8,
+                self.blocks.len() * BLOCK_SIZE,
+            )
+        }
+    }
+}
+
+/// Open a backing target for direct IO, bypassing the page cache.
+///
+/// Not all filesystems support direct IO (e.g. tmpfs and some FUSE or network filesystems), they
+/// either reject the open call or the first IO with EINVAL. In that case, the target is opened
+/// for buffered IO instead.
+///
+/// If `writable` is false, the target is opened read only, guaranteeing it is never modified.
+pub fn open(path: &Path, writable: bool) -> io::Result<Target> {
+    // The value of O_DIRECT differs between architectures, so it is taken from the platform
+    // definitions rather than hardcoded.
+    match open_with_flags(path, writable, OFlag::O_DIRECT) {
+        Ok(file) => match probe_direct_io(&file) {
+            Ok(()) => return Ok(Target { file, direct: true }),
+            Err(e) if e.raw_os_error() != Some(nix::Error::EINVAL as i32) => return Err(e),
+            Err(_) => {}
+        },
+        Err(e) if e.raw_os_error() != Some(nix::Error::EINVAL as i32) => return Err(e),
+        Err(_) => {}
+    }
+
+    log::warn!(
+        "{} does not support direct IO, falling back to buffered IO",
+        path.display()
+    );
+
+    Ok(Target {
+        file: open_with_flags(path, writable, OFlag::empty())?,
+        direct: false,
+    })
+}
+
+/// Open another file description of a target which was opened before, in the same mode.
+pub fn reopen(path: &Path, writable: bool, direct: bool) -> io::Result<File> {
+    let flags = if direct {
+        OFlag::O_DIRECT
+    } else {
+        OFlag::empty()
+    };
+    open_with_flags(path, writable, flags)
+}
+
+/// Open a file for reading, and optionally writing, with the given additional flags.
+fn open_with_flags(path: &Path, writable: bool, flags: OFlag) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .custom_flags(flags.bits())
+        .open(path)
+}
+
+/// Issue an aligned read at the start of the file, to verify the filesystem accepts direct IO.
+fn probe_direct_io(file: &File) -> io::Result<()> {
+    let mut buf = AlignedBuf::zeroed(BLOCK_SIZE);
+    file.read_at(&mut buf, 0).map(|_| ())
+}
@@ -0,0 +1,797 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::prelude::FileExt},
+    sync::Mutex,
+};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+use crate::target::{AlignedBuf, BLOCK_SIZE};
+
+/// Size of a chunk, the unit in which space on the target is allocated.
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+/// Magic at the start of the superblock of a thin provisioned target.
+const MAGIC: [u8; 8] = *b"VBLKTHIN";
+/// Version of the on-disk format.
+const VERSION: u32 = 1;
+/// Size of the superblock, the mapping table follows it.
+const SUPERBLOCK_SIZE: u64 = BLOCK_SIZE as u64;
+/// Size of an entry in the mapping table.
+const ENTRY_SIZE: u64 = 8;
+/// Table entry of a chunk whose mapping is damaged, only used in memory. IO to it fails with EIO.
+const BAD: u64 = u64::MAX;
+
+/// Mapping of a thin provisioned device to its target.
+///
+/// The virtual device is split in chunks of [`CHUNK_SIZE`]. A chunk only gets space on the
+/// target when it is first written, so a large device can live on a small target until it is
+/// filled. Chunks which were never written read as zeroes.
+///
+/// The mapping is stored at the start of the target, so it survives restarts:
+///
+/// - A superblock of [`SUPERBLOCK_SIZE`] bytes, holding the magic, format version, chunk size,
+///   device size, start of the data area and the [`BaseId`] of the base image (zero without
+///   one), followed by a checksum of those fields. All integers are little endian.
+/// - The mapping table, with an entry of [`ENTRY_SIZE`] bytes per virtual chunk, holding the
+///   physical chunk + 1, or 0 if the chunk is not allocated.
+/// - The data area, starting at the first chunk boundary after the table.
+///
+/// A new chunk is initialized and its table entry is written before the write which caused the
+/// allocation is submitted. Only reserving the chunk happens under the lock of the map, the IO
+/// is done by the caller (see [`ThinMap::reserve`]).
+#[derive(Debug)]
+pub struct ThinMap {
+    /// Size of the virtual device in bytes.
+    size: u64,
+    /// Offset of the data area on the target.
+    data_start: u64,
+    /// Base image the device was created with, if any.
+    base: Option<BaseId>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Physical chunk + 1 per virtual chunk, 0 if it is not allocated, or [`BAD`].
+    table: Vec<u64>,
+    allocator: Allocator,
+    /// Virtual chunks which are reserved, but not committed yet.
+    pending: HashSet<usize>,
+    /// Blocks of the table which are being written.
+    storing: HashSet<usize>,
+}
+
+/// Result of [`ThinMap::reserve`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reservation {
+    /// The chunk is allocated, the offset on the target is given.
+    Mapped(u64),
+    /// A new chunk was reserved. It must be initialized and its table block stored (see
+    /// [`ThinMap::table_block`]) before it is committed, or it must be released.
+    New(NewChunk),
+    /// Another write is setting up the chunk, try again later.
+    Busy,
+}
+
+/// A reserved chunk which is not visible yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NewChunk {
+    virt: usize,
+    phys: u64,
+    /// Offset of the chunk on the target.
+    pub target_off: u64,
+    /// Offset of the chunk on the device.
+    pub off: u64,
+}
+
+/// Allocator of physical chunks on the target.
+#[derive(Debug)]
+struct Allocator {
+    /// Lowest physical chunk which was never handed out. Everything from here on is free.
+    next: u64,
+    /// Amount of chunks which fit on the target, None if the target grows as needed (e.g. a
+    /// regular file).
+    capacity: Option<u64>,
+    /// Chunks below next which are free again.
+    free: BTreeSet<u64>,
+}
+
+/// Identifies the base image of a thin device, so the device is not used with another one, whose
+/// data would show through in the chunks which were never written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BaseId {
+    /// Size of the base image in bytes.
+    pub size: u64,
+    /// Hash of the first chunk of the base image.
+    pub hash: u64,
+}
+
+impl BaseId {
+    /// Identify a base image of `size` bytes.
+    pub fn new(file: &File, size: u64) -> io::Result<BaseId> {
+        let mut buf = AlignedBuf::zeroed(CHUNK_SIZE as usize);
+        let n = read_full_at(file, &mut buf, 0)?;
+        Ok(BaseId {
+            size,
+            hash: checksum(&buf[..n]),
+        })
+    }
+}
+
+impl ThinMap {
+    /// Load the mapping stored on the target. If the target is blank, a new mapping is stored
+    /// for a device of `size` bytes with the given base image instead. If the target holds a
+    /// mapping for a different size, it is rejected.
+    ///
+    /// `target_size` is the size of the target if it is fixed (i.e. a block device).
+    ///
+    /// In `rescue` mode, a damaged mapping is loaded as far as possible, for read only access. A
+    /// superblock checksum mismatch is ignored, and chunks with an unreadable or invalid table
+    /// entry fail with EIO. A blank target is never formatted in rescue mode, nor if the file is
+    /// opened read only (e.g. in audit mode).
+    pub fn open(
+        file: &File,
+        size: Option<u64>,
+        target_size: Option<u64>,
+        base: Option<BaseId>,
+        rescue: bool,
+    ) -> io::Result<ThinMap> {
+        let mut superblock = AlignedBuf::zeroed(SUPERBLOCK_SIZE as usize);
+        let n = read_full_at(file, &mut superblock, 0)?;
+
+        if superblock[..8] != MAGIC {
+            if superblock[..n].iter().any(|b| *b != 0) {
+                return Err(invalid_data(
+                    "target holds data, but no thin provisioning superblock",
+                ));
+            }
+            if rescue || is_read_only(file)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "target is blank, a thin device is only formatted on a writable target",
+                ));
+            }
+            let size = size.ok_or_else(|| invalid_data("size of a new thin device is required"))?;
+            log::warn!("formatting target for a thin device of {size} bytes");
+            return ThinMap::format(file, size, target_size, base);
+        }
+
+        let field = |i: usize| u64::from_le_bytes(superblock[i..i + 8].try_into().unwrap());
+        let version = u32::from_le_bytes(superblock[8..12].try_into().unwrap());
+        if field(56) != checksum(&superblock[..56]) {
+            if !rescue {
+                return Err(invalid_data("superblock checksum mismatch"));
+            }
+            log::warn!("superblock checksum mismatch, using the superblock anyway");
+        }
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported format version {version}"
+            )));
+        }
+        if field(16) != CHUNK_SIZE {
+            return Err(invalid_data(&format!(
+                "unsupported chunk size {}",
+                field(16)
+            )));
+        }
+        let stored_size = field(24);
+        if size.is_some_and(|size| size != stored_size) {
+            return Err(invalid_data(&format!(
+                "target holds a thin device of {stored_size} bytes"
+            )));
+        }
+        let data_start = field(32);
+        if data_start != data_start_for(stored_size) {
+            return Err(invalid_data("data area overlaps the mapping table"));
+        }
+
+        let base = (field(40) != 0).then(|| BaseId {
+            size: field(40),
+            hash: field(48),
+        });
+
+        let nr_chunks = stored_size.div_ceil(CHUNK_SIZE) as usize;
+        let capacity = target_size.map(|target_size| capacity(target_size, data_start));
+        let table = if rescue {
+            let mut table = read_table_rescue(file, nr_chunks);
+            mark_bad(&mut table, capacity);
+            table
+        } else {
+            let mut raw = AlignedBuf::zeroed(nr_chunks * ENTRY_SIZE as usize);
+            file.read_exact_at(&mut raw, SUPERBLOCK_SIZE)?;
+            raw.chunks_exact(ENTRY_SIZE as usize)
+                .take(nr_chunks)
+                .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+                .collect()
+        };
+
+        let allocator = Allocator::load(&table, capacity)?;
+
+        Ok(ThinMap {
+            size: stored_size,
+            data_start,
+            base,
+            state: Mutex::new(State {
+                table,
+                allocator,
+                pending: HashSet::new(),
+                storing: HashSet::new(),
+            }),
+        })
+    }
+
+    /// Store a new, empty mapping on the target.
+    fn format(
+        file: &File,
+        size: u64,
+        target_size: Option<u64>,
+        base: Option<BaseId>,
+    ) -> io::Result<ThinMap> {
+        let nr_chunks = size.div_ceil(CHUNK_SIZE) as usize;
+        let data_start = data_start_for(size);
+        let capacity = target_size.map(|target_size| capacity(target_size, data_start));
+        if capacity == Some(0) {
+            return Err(io::Error::from_raw_os_error(nix::Error::ENOSPC as i32));
+        }
+
+        // Clear the table first, so a superblock never points to a stale table.
+        let table = AlignedBuf::zeroed(nr_chunks * ENTRY_SIZE as usize);
+        file.write_all_at(&table, SUPERBLOCK_SIZE)?;
+
+        let mut superblock = AlignedBuf::zeroed(SUPERBLOCK_SIZE as usize);
+        superblock[..8].copy_from_slice(&MAGIC);
+        superblock[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        superblock[16..24].copy_from_slice(&CHUNK_SIZE.to_le_bytes());
+        superblock[24..32].copy_from_slice(&size.to_le_bytes());
+        superblock[32..40].copy_from_slice(&data_start.to_le_bytes());
+        if let Some(base) = base {
+            superblock[40..48].copy_from_slice(&base.size.to_le_bytes());
+            superblock[48..56].copy_from_slice(&base.hash.to_le_bytes());
+        }
+        let sum = checksum(&superblock[..56]);
+        superblock[56..64].copy_from_slice(&sum.to_le_bytes());
+        file.write_all_at(&superblock, 0)?;
+        file.sync_all()?;
+
+        Ok(ThinMap {
+            size,
+            data_start,
+            base,
+            state: Mutex::new(State {
+                table: vec![0; nr_chunks],
+                allocator: Allocator {
+                    next: 0,
+                    capacity,
+                    free: BTreeSet::new(),
+                },
+                pending: HashSet::new(),
+                storing: HashSet::new(),
+            }),
+        })
+    }
+
+    /// Size of the virtual device in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Check that the device is used with the base image it was created with.
+    pub fn check_base(&self, base: Option<BaseId>) -> io::Result<()> {
+        if self.base == base {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            match base {
+                Some(_) => "the target belongs to a device with another or no base image",
+                None => "the target belongs to a device with a base image",
+            },
+        ))
+    }
+
+    /// Offset of the first data chunk on the target, everything before it is metadata.
+    pub fn data_start(&self) -> u64 {
+        self.data_start
+    }
+
+    /// Translate an offset on the virtual device to an offset on the target. Returns None if
+    /// the chunk holding the offset is not allocated.
+    pub fn lookup(&self, off: u64) -> io::Result<Option<u64>> {
+        let state = self.state.lock().unwrap();
+        match state.table.get((off / CHUNK_SIZE) as usize) {
+            Some(&BAD) => Err(io::Error::from_raw_os_error(nix::Error::EIO as i32)),
+            Some(&entry) if entry != 0 => Ok(Some(self.target_offset(entry - 1, off))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Translate an offset on the virtual device to an offset on the target for a write,
+    /// reserving a new chunk if the chunk holding it is not allocated.
+    ///
+    /// A new chunk must be initialized by the caller, so the parts of it which are not written
+    /// don't expose stale data, and the table block holding its entry must be stored, before it
+    /// is committed. Until then, reads of the chunk see it as unallocated, and writes to it are
+    /// [`Reservation::Busy`].
+    pub fn reserve(&self, off: u64) -> io::Result<Reservation> {
+        let mut state = self.state.lock().unwrap();
+        let virt = (off / CHUNK_SIZE) as usize;
+        match state.table.get(virt) {
+            Some(&BAD) => return Err(io::Error::from_raw_os_error(nix::Error::EIO as i32)),
+            Some(&entry) if entry != 0 => {
+                return Ok(Reservation::Mapped(self.target_offset(entry - 1, off)))
+            }
+            Some(_) if state.pending.contains(&virt) => return Ok(Reservation::Busy),
+            Some(_) => {}
+            None => return Err(io::Error::from_raw_os_error(nix::Error::EINVAL as i32)),
+        }
+
+        let phys = state
+            .allocator
+            .alloc()
+            .ok_or_else(|| io::Error::from_raw_os_error(nix::Error::ENOSPC as i32))?;
+        state.pending.insert(virt);
+        Ok(Reservation::New(NewChunk {
+            virt,
+            phys,
+            target_off: self.target_offset(phys, 0),
+            off: virt as u64 * CHUNK_SIZE,
+        }))
+    }
+
+    /// The block of the table holding the entry of a reserved chunk, with the chunk mapped, and
+    /// its offset on the target. Returns None if the block is being stored for another chunk,
+    /// in which case this has to be tried again later, so the block holds both entries.
+    pub fn table_block(&self, chunk: &NewChunk) -> Option<(u64, AlignedBuf)> {
+        let mut state = self.state.lock().unwrap();
+        let per_block = BLOCK_SIZE / ENTRY_SIZE as usize;
+        let first = chunk.virt - chunk.virt % per_block;
+        if !state.storing.insert(first / per_block) {
+            return None;
+        }
+
+        let mut block = AlignedBuf::zeroed(BLOCK_SIZE);
+        for (i, raw) in block.chunks_exact_mut(ENTRY_SIZE as usize).enumerate() {
+            let entry = match state.table.get(first + i) {
+                _ if first + i == chunk.virt => chunk.phys + 1,
+                // Bad entries are only known in memory, their stored value is unknown.
+                Some(&BAD) | None => 0,
+                Some(&entry) => entry,
+            };
+            raw.copy_from_slice(&entry.to_le_bytes());
+        }
+        Some((SUPERBLOCK_SIZE + first as u64 * ENTRY_SIZE, block))
+    }
+
+    /// Make a reserved chunk visible, once its table block is stored.
+    pub fn commit(&self, chunk: NewChunk) {
+        let mut state = self.state.lock().unwrap();
+        state.table[chunk.virt] = chunk.phys + 1;
+        state.pending.remove(&chunk.virt);
+        let per_block = BLOCK_SIZE / ENTRY_SIZE as usize;
+        state.storing.remove(&(chunk.virt / per_block));
+    }
+
+    /// Release a reserved chunk which could not be set up. `storing` is whether its table block
+    /// was taken with [`ThinMap::table_block`].
+    pub fn release(&self, chunk: NewChunk, storing: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.remove(&chunk.virt);
+        if storing {
+            let per_block = BLOCK_SIZE / ENTRY_SIZE as usize;
+            state.storing.remove(&(chunk.virt / per_block));
+        }
+        state.allocator.release(chunk.phys);
+    }
+
+    /// Offset on the target of `off` in physical chunk `phys`.
+    fn target_offset(&self, phys: u64, off: u64) -> u64 {
+        self.data_start + phys * CHUNK_SIZE + off % CHUNK_SIZE
+    }
+}
+
+impl Allocator {
+    /// Rebuild the allocator from a stored mapping table, validating that every physical chunk
+    /// is on the target and used only once. Bad entries are skipped.
+    fn load(table: &[u64], capacity: Option<u64>) -> io::Result<Allocator> {
+        let mut used = BTreeSet::new();
+        for &entry in table.iter().filter(|entry| **entry != 0 && **entry != BAD) {
+            let phys = entry - 1;
+            if capacity.is_some_and(|capacity| phys >= capacity) {
+                return Err(invalid_data(&format!("chunk {phys} is beyond the target")));
+            }
+            if !used.insert(phys) {
+                return Err(invalid_data(&format!("chunk {phys} is mapped twice")));
+            }
+        }
+
+        let next = used.last().map(|last| last + 1).unwrap_or(0);
+        Ok(Allocator {
+            next,
+            capacity,
+            free: (0..next).filter(|phys| !used.contains(phys)).collect(),
+        })
+    }
+
+    /// Hand out a free chunk. Freed chunks are reused first, lowest first, to keep the target
+    /// compact.
+    fn alloc(&mut self) -> Option<u64> {
+        if let Some(chunk) = self.free.pop_first() {
+            return Some(chunk);
+        }
+        if self.capacity.is_some_and(|capacity| self.next >= capacity) {
+            return None;
+        }
+        self.next += 1;
+        Some(self.next - 1)
+    }
+
+    /// Return a chunk to the allocator.
+    fn release(&mut self, chunk: u64) {
+        if chunk + 1 == self.next {
+            self.next -= 1;
+        } else {
+            self.free.insert(chunk);
+        }
+    }
+}
+
+/// Read the mapping table of `nr_chunks` entries block by block. The entries in a block which
+/// can't be read are marked as bad.
+fn read_table_rescue(file: &File, nr_chunks: usize) -> Vec<u64> {
+    let per_block = BLOCK_SIZE / ENTRY_SIZE as usize;
+    let mut table = Vec::with_capacity(nr_chunks);
+    let mut block = AlignedBuf::zeroed(BLOCK_SIZE);
+    while table.len() < nr_chunks {
+        let count = per_block.min(nr_chunks - table.len());
+        let off = SUPERBLOCK_SIZE + table.len() as u64 * ENTRY_SIZE;
+        match file.read_exact_at(&mut block, off) {
+            Ok(()) => table.extend(
+                block
+                    .chunks_exact(ENTRY_SIZE as usize)
+                    .take(count)
+                    .map(|entry| u64::from_le_bytes(entry.try_into().unwrap())),
+            ),
+            Err(e) => {
+                log::warn!("could not read mapping table at offset {off}: {e}");
+                table.extend(std::iter::repeat_n(BAD, count));
+            }
+        }
+    }
+    table
+}
+
+/// Mark entries of a damaged table which are beyond the target, or share a physical chunk with
+/// another entry, as bad. It is unknown which of the entries sharing a chunk is correct, so all
+/// of them are marked.
+fn mark_bad(table: &mut [u64], capacity: Option<u64>) {
+    let mut users: HashMap<u64, usize> = HashMap::new();
+    for &entry in table.iter().filter(|entry| **entry != 0 && **entry != BAD) {
+        *users.entry(entry).or_default() += 1;
+    }
+    let mut nr_bad = 0;
+    for entry in table
+        .iter_mut()
+        .filter(|entry| **entry != 0 && **entry != BAD)
+    {
+        let beyond = capacity.is_some_and(|capacity| *entry > capacity);
+        if beyond || users[entry] > 1 {
+            *entry = BAD;
+            nr_bad += 1;
+        }
+    }
+    if nr_bad > 0 {
+        log::warn!("{nr_bad} chunks have an invalid mapping and fail with EIO");
+    }
+}
+
+/// Split the range `off..off + len` in pieces which don't cross a boundary of `chunk_size`.
+pub fn split(off: u64, len: u64, chunk_size: u64) -> impl Iterator<Item = (u64, u64)> {
+    let end = off + len;
+    let mut cur = off;
+    std::iter::from_fn(move || {
+        if cur >= end {
+            return None;
+        }
+        let next = (cur - cur % chunk_size).saturating_add(chunk_size).min(end);
+        let piece = (cur, next - cur);
+        cur = next;
+        Some(piece)
+    })
+}
+
+/// Offset of the data area of a device of the given size.
+fn data_start_for(size: u64) -> u64 {
+    (SUPERBLOCK_SIZE + size.div_ceil(CHUNK_SIZE) * ENTRY_SIZE).next_multiple_of(CHUNK_SIZE)
+}
+
+/// Amount of chunks in the data area of a target of the given size.
+fn capacity(target_size: u64, data_start: u64) -> u64 {
+    target_size.saturating_sub(data_start) / CHUNK_SIZE
+}
+
+/// FNV-1a hash, used to detect a damaged superblock.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Read into buf at the given offset until it is full or the end of the file is reached.
+/// Returns the amount of bytes read.
+fn read_full_at(file: &File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read_at(&mut buf[n..], off + n as u64) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Whether a file is opened read only.
+fn is_read_only(file: &File) -> io::Result<bool> {
+    let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    Ok(flags & OFlag::O_ACCMODE == OFlag::O_RDONLY)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 64 * CHUNK_SIZE;
+
+    /// An empty file which is removed once it is closed.
+    fn temp_file(name: &str) -> File {
+        let path = std::env::temp_dir().join(format!("vblock-thin-{}-{name}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    /// Overwrite a superblock field and fix up the checksum.
+    fn set_field(file: &File, i: usize, value: u64) {
+        let mut superblock = vec![0; SUPERBLOCK_SIZE as usize];
+        file.read_exact_at(&mut superblock, 0).unwrap();
+        superblock[i..i + 8].copy_from_slice(&value.to_le_bytes());
+        let sum = checksum(&superblock[..56]);
+        superblock[56..64].copy_from_slice(&sum.to_le_bytes());
+        file.write_all_at(&superblock, 0).unwrap();
+    }
+
+    /// Allocate the chunk holding `off` and store its table entry.
+    fn allocate(map: &ThinMap, file: &File, off: u64) -> u64 {
+        let Reservation::New(chunk) = map.reserve(off).unwrap() else {
+            panic!("chunk at {off} is already allocated");
+        };
+        let (table_off, block) = map.table_block(&chunk).unwrap();
+        file.write_all_at(&block, table_off).unwrap();
+        map.commit(chunk);
+        map.lookup(off).unwrap().unwrap()
+    }
+
+    #[test]
+    fn format_and_reopen() {
+        let file = temp_file("reopen");
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        assert_eq!(map.size(), SIZE);
+        assert_eq!(map.data_start(), CHUNK_SIZE);
+        assert_eq!(map.base, None);
+
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        assert_eq!(map.size(), SIZE);
+        // The size is taken from the superblock if it is not given.
+        let map = ThinMap::open(&file, None, None, None, false).unwrap();
+        assert_eq!(map.size(), SIZE);
+        assert_eq!(map.data_start(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn blank_target_needs_size() {
+        let file = temp_file("no-size");
+        assert!(ThinMap::open(&file, None, None, None, false).is_err());
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, true).is_err());
+    }
+
+    #[test]
+    fn read_only_blank_target_is_not_formatted() {
+        let path = std::env::temp_dir().join(format!("vblock-thin-{}-ro", std::process::id()));
+        File::create(&path).unwrap().set_len(SIZE).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let e = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let mut superblock = vec![0; SUPERBLOCK_SIZE as usize];
+        file.read_exact_at(&mut superblock, 0).unwrap();
+        assert!(superblock.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn reject_data_without_magic() {
+        let file = temp_file("magic");
+        ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        file.write_all_at(b"NOTTHIN!", 0).unwrap();
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, false).is_err());
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, true).is_err());
+    }
+
+    #[test]
+    fn reject_checksum_mismatch_unless_rescue() {
+        let file = temp_file("checksum");
+        ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        file.write_all_at(&[1], 60).unwrap();
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, false).is_err());
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, true).is_ok());
+    }
+
+    #[test]
+    fn reject_other_size() {
+        let file = temp_file("size");
+        ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        assert!(ThinMap::open(&file, Some(SIZE * 2), None, None, false).is_err());
+    }
+
+    #[test]
+    fn reject_data_start_in_table() {
+        let file = temp_file("data-start");
+        ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        set_field(&file, 32, SUPERBLOCK_SIZE);
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, false).is_err());
+    }
+
+    #[test]
+    fn allocate_until_full() {
+        let file = temp_file("full");
+        let target_size = CHUNK_SIZE * 3;
+        let map = ThinMap::open(&file, Some(SIZE), Some(target_size), None, false).unwrap();
+        assert_eq!(allocate(&map, &file, 0), CHUNK_SIZE);
+        assert_eq!(
+            allocate(&map, &file, 10 * CHUNK_SIZE + 7),
+            2 * CHUNK_SIZE + 7
+        );
+        let err = map.reserve(20 * CHUNK_SIZE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::Error::ENOSPC as i32));
+        // Allocated chunks can still be written.
+        assert_eq!(
+            map.reserve(CHUNK_SIZE - 1).unwrap(),
+            Reservation::Mapped(2 * CHUNK_SIZE - 1)
+        );
+        assert!(map.reserve(SIZE).is_err());
+    }
+
+    #[test]
+    fn reserved_chunk_is_not_visible() {
+        let file = temp_file("reserve");
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        let Reservation::New(chunk) = map.reserve(0).unwrap() else {
+            panic!("new chunk expected");
+        };
+        assert_eq!(map.lookup(0).unwrap(), None);
+        assert_eq!(map.reserve(100).unwrap(), Reservation::Busy);
+
+        // A chunk whose entry is in the same table block waits for the block to be stored.
+        let Reservation::New(other) = map.reserve(CHUNK_SIZE).unwrap() else {
+            panic!("new chunk expected");
+        };
+        let (_, block) = map.table_block(&chunk).unwrap();
+        assert!(map.table_block(&other).is_none());
+        assert_eq!(block[..8], 1u64.to_le_bytes());
+        assert_eq!(block[8..16], 0u64.to_le_bytes());
+        map.commit(chunk);
+        let (_, block) = map.table_block(&other).unwrap();
+        assert_eq!(block[..8], 1u64.to_le_bytes());
+        assert_eq!(block[8..16], 2u64.to_le_bytes());
+
+        // A released chunk is handed out again.
+        map.release(other, true);
+        assert_eq!(map.lookup(CHUNK_SIZE).unwrap(), None);
+        let Reservation::New(chunk) = map.reserve(5 * CHUNK_SIZE).unwrap() else {
+            panic!("new chunk expected");
+        };
+        assert_eq!(chunk.target_off, 2 * CHUNK_SIZE);
+        assert_eq!(chunk.off, 5 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn entries_persist() {
+        let file = temp_file("persist");
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        allocate(&map, &file, 3 * CHUNK_SIZE);
+        allocate(&map, &file, 63 * CHUNK_SIZE + 5);
+
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        assert_eq!(map.lookup(0).unwrap(), None);
+        assert_eq!(
+            map.lookup(3 * CHUNK_SIZE + 1).unwrap(),
+            Some(CHUNK_SIZE + 1)
+        );
+        assert_eq!(map.lookup(63 * CHUNK_SIZE).unwrap(), Some(2 * CHUNK_SIZE));
+        // New chunks don't reuse the loaded ones.
+        assert_eq!(allocate(&map, &file, 0), 3 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn reject_chunk_mapped_twice() {
+        let file = temp_file("twice");
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        allocate(&map, &file, 0);
+        file.write_all_at(&1u64.to_le_bytes(), SUPERBLOCK_SIZE + ENTRY_SIZE)
+            .unwrap();
+        assert!(ThinMap::open(&file, Some(SIZE), None, None, false).is_err());
+
+        let map = ThinMap::open(&file, Some(SIZE), None, None, true).unwrap();
+        for off in [0, CHUNK_SIZE] {
+            let err = map.lookup(off).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(nix::Error::EIO as i32));
+        }
+    }
+
+    #[test]
+    fn rescue_marks_bad_entries() {
+        let mut table = vec![1, 2, 2, 0, 5, 4];
+        mark_bad(&mut table, Some(4));
+        assert_eq!(table, vec![1, BAD, BAD, 0, BAD, 4]);
+
+        let mut table = vec![1, 7, 0];
+        mark_bad(&mut table, None);
+        assert_eq!(table, vec![1, 7, 0]);
+    }
+
+    #[test]
+    fn base_id_is_stored() {
+        let base_file = temp_file("base");
+        base_file.write_all_at(b"base image", 0).unwrap();
+        let base = BaseId::new(&base_file, 4096).unwrap();
+        let other_file = temp_file("other-base");
+        other_file.write_all_at(b"other image", 0).unwrap();
+        assert_ne!(BaseId::new(&other_file, 4096).unwrap(), base);
+
+        let file = temp_file("with-base");
+        ThinMap::open(&file, Some(SIZE), None, Some(base), false).unwrap();
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        assert_eq!(map.base, Some(base));
+        assert!(map.check_base(Some(base)).is_ok());
+        assert!(map.check_base(None).is_err());
+        let other = BaseId {
+            hash: base.hash + 1,
+            ..base
+        };
+        assert!(map.check_base(Some(other)).is_err());
+
+        let file = temp_file("without-base");
+        let map = ThinMap::open(&file, Some(SIZE), None, None, false).unwrap();
+        assert!(map.check_base(None).is_ok());
+        assert!(map.check_base(Some(base)).is_err());
+    }
+
+    #[test]
+    fn split_at_chunk_boundaries() {
+        assert_eq!(split(0, 0, 8).count(), 0);
+        assert_eq!(split(3, 4, 8).collect::<Vec<_>>(), vec![(3, 4)]);
+        assert_eq!(
+            split(6, 12, 8).collect::<Vec<_>>(),
+            vec![(6, 2), (8, 8), (16, 2)]
+        );
+        assert_eq!(split(8, 16, 8).collect::<Vec<_>>(), vec![(8, 8), (16, 8)]);
+        assert_eq!(
+            split(u64::MAX - 4, 4, 1 << 63).collect::<Vec<_>>(),
+            vec![(u64::MAX - 4, 4)]
+        );
+    }
+}
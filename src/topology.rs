@@ -0,0 +1,134 @@
+use std::{
+    fs, io,
+    os::unix::prelude::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use nix::sys::stat::{major, minor};
+
+/// Default amount of queues for a device backed by the given target.
+///
+/// One queue is used per NUMA node, so IO submitted on a node is handled on that node (see
+/// [`queue_cpus`]), limited to the amount of online CPUs. If the target is a block device, the
+/// amount of queues is also limited to its hardware queues, as more queues than that would
+/// contend on the same hardware queue anyway. If the topology can't be determined, a single
+/// queue is used.
+pub fn default_queues(target: &Path) -> u32 {
+    let nodes = read_list("/sys/devices/system/node/online").map_or(1, |nodes| nodes.len() as u32);
+    let cpus = read_list("/sys/devices/system/cpu/online").map_or(1, |cpus| cpus.len() as u32);
+    let mut queues = nodes.min(cpus);
+
+    match hw_queues(target) {
+        Ok(Some(hw_queues)) => queues = queues.min(hw_queues),
+        Ok(None) => {}
+        Err(e) => log::debug!(
+            "could not find hardware queues of {}: {e}",
+            target.display()
+        ),
+    }
+
+    queues.max(1)
+}
+
+/// CPUs to pin the thread of every queue to. If there is a queue per NUMA node, the thread of a
+/// queue runs on the online CPUs of its node, so its IO buffers and rings stay local to the node.
+/// Otherwise, or if the topology can't be determined, no queue is pinned, and the queue threads
+/// keep the affinity libublk gives them, which are the CPUs submitting to the queue.
+///
+/// Interrupt affinity of the target is left to the kernel. Completions of the target are handled
+/// on the CPUs its interrupts are steered to, which with managed interrupts are the CPUs of the
+/// hardware queue of the submitting CPU, on the same node.
+pub fn queue_cpus(nr_queues: u32) -> Vec<Vec<u32>> {
+    let (Some(nodes), Some(online)) = (
+        read_list("/sys/devices/system/node/online"),
+        read_list("/sys/devices/system/cpu/online"),
+    ) else {
+        return Vec::new();
+    };
+    if nodes.len() < 2 || nodes.len() != nr_queues as usize {
+        return Vec::new();
+    }
+
+    let mut queue_cpus = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let Some(cpus) = read_list(&format!("/sys/devices/system/node/node{node}/cpulist")) else {
+            return Vec::new();
+        };
+        let cpus: Vec<u32> = cpus
+            .into_iter()
+            .filter(|cpu| online.contains(cpu))
+            .collect();
+        // A node with only memory can't run a queue.
+        if cpus.is_empty() {
+            return Vec::new();
+        }
+        queue_cpus.push(cpus);
+    }
+    queue_cpus
+}
+
+/// Amount of hardware queues of a block device target. Returns None if the target is not a block
+/// device, or not a multiqueue device.
+fn hw_queues(target: &Path) -> io::Result<Option<u32>> {
+    let meta = fs::metadata(target)?;
+    if !meta.file_type().is_block_device() {
+        return Ok(None);
+    }
+
+    let rdev = meta.rdev();
+    let mut dev = fs::canonicalize(PathBuf::from(format!(
+        "/sys/dev/block/{}:{}",
+        major(rdev),
+        minor(rdev)
+    )))?;
+    // The queues of a partition are those of the whole disk.
+    if dev.join("partition").exists() {
+        dev.pop();
+    }
+
+    let mq = match fs::read_dir(dev.join("mq")) {
+        Ok(mq) => mq,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    Ok(Some(mq.count() as u32))
+}
+
+/// Read the entries of a sysfs list file.
+fn read_list(path: &str) -> Option<Vec<u32>> {
+    parse_list(&fs::read_to_string(path).ok()?)
+}
+
+/// Parse the entries of a sysfs list, e.g. `0-3,8-11`.
+fn parse_list(list: &str) -> Option<Vec<u32>> {
+    let mut entries = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.parse::<u32>().ok()?, end.parse::<u32>().ok()?);
+                if start > end {
+                    return None;
+                }
+                entries.extend(start..=end);
+            }
+            None => entries.push(range.parse::<u32>().ok()?),
+        }
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sysfs_list() {
+        assert_eq!(parse_list("0\n"), Some(vec![0]));
+        assert_eq!(parse_list("0-3,8-9\n"), Some(vec![0, 1, 2, 3, 8, 9]));
+        assert_eq!(parse_list("1,3-4"), Some(vec![1, 3, 4]));
+        assert_eq!(parse_list("\n"), Some(vec![]));
+        assert_eq!(parse_list("3-1"), None);
+        assert_eq!(parse_list("a-b"), None);
+    }
+}
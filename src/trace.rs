@@ -0,0 +1,43 @@
+use std::fmt;
+
+use libublk::io::UblkIOCtx;
+
+/// Identifies a single request in log messages, so it can be followed through retries and
+/// splits.
+///
+/// A request is identified by its queue and tag, and a sequence number counting the requests
+/// handled by that tag. The sequence number is stored in the target data of the user data of
+/// every IO submitted for the request, so it can be recovered from the user data alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceId {
+    queue: u16,
+    tag: u16,
+    seq: u16,
+}
+
+impl TraceId {
+    pub fn new(queue: u16, tag: u16, seq: u16) -> TraceId {
+        TraceId { queue, tag, seq }
+    }
+
+    /// Recover the trace id from the user data of an IO submitted on a queue.
+    pub fn from_user_data(queue: u16, user_data: u64) -> TraceId {
+        TraceId {
+            queue,
+            tag: UblkIOCtx::user_data_to_tag(user_data) as u16,
+            // libublk stores the 16 bits of target data starting at bit 24.
+            seq: (user_data >> 24) as u16,
+        }
+    }
+
+    /// Sequence number of the request, to be passed as target data in the user data.
+    pub fn seq(&self) -> u16 {
+        self.seq
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "q{}:t{}:{}", self.queue, self.tag, self.seq)
+    }
+}
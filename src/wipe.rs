@@ -0,0 +1,122 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    ops::Range,
+    os::{
+        fd::AsRawFd,
+        unix::prelude::{FileExt, FileTypeExt},
+    },
+    path::Path,
+};
+
+use crate::{geometry::Slice, kernel, layout};
+
+/// Amount of zeroes written to a regular file at once.
+const ZERO_CHUNK_SIZE: usize = 1 << 20;
+
+/// How a target was wiped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WipeMethod {
+    /// The device discarded the data in a way it can't be recovered.
+    SecureDiscard,
+    /// The device was zeroed by the kernel, possibly offloaded to the device.
+    ZeroOut,
+    /// The file was overwritten with zeroes.
+    Overwrite,
+}
+
+/// Destroy all data on a target, or only on the given slice of it if the device used just that.
+///
+/// Block devices are securely discarded if they support it, and zeroed otherwise. Regular files
+/// are overwritten with zeroes. Either way, the data is durable on the target once this returns.
+/// Note that overwriting a file does not guarantee the old data is gone from the underlying
+/// storage, e.g. on copy on write filesystems or flash with wear leveling.
+pub fn wipe(path: &Path, slice: Option<Slice>) -> io::Result<WipeMethod> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let layout = layout::Layout::new(&file).map_err(io::Error::other)?;
+    let range = match slice {
+        Some(slice) => {
+            let end = slice
+                .size_limit
+                .map_or(layout.size, |limit| slice.offset + limit);
+            slice.offset.min(layout.size)..end.min(layout.size)
+        }
+        None => 0..layout.size,
+    };
+
+    let method = if file.metadata()?.file_type().is_block_device() {
+        // Discards work on whole blocks, the start and end of a slice may be in the middle of
+        // one. The partial blocks at either end are overwritten instead.
+        let blocks = whole_blocks(&range, layout.logical_block_size);
+        if blocks.is_empty() {
+            overwrite(&file, range)?;
+            WipeMethod::Overwrite
+        } else {
+            let method = wipe_block_device(&file, blocks.clone())?;
+            overwrite(&file, range.start..blocks.start)?;
+            overwrite(&file, blocks.end..range.end)?;
+            method
+        }
+    } else {
+        overwrite(&file, range)?;
+        WipeMethod::Overwrite
+    };
+    file.sync_all()?;
+
+    Ok(method)
+}
+
+/// The part of a range covering whole blocks of `block` bytes.
+fn whole_blocks(range: &Range<u64>, block: u64) -> Range<u64> {
+    let start = range.start.next_multiple_of(block);
+    let end = range.end - range.end % block;
+    start..end.max(start)
+}
+
+fn wipe_block_device(file: &File, range: Range<u64>) -> io::Result<WipeMethod> {
+    let range = [range.start, range.end - range.start];
+    // SAFETY: ioctl on a valid file descriptor with a pointer to a valid range.
+    match unsafe { kernel::ioctl_blksecdiscard(file.as_raw_fd(), &range) } {
+        Ok(_) => return Ok(WipeMethod::SecureDiscard),
+        Err(nix::Error::EOPNOTSUPP) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // SAFETY: see above.
+    unsafe { kernel::ioctl_blkzeroout(file.as_raw_fd(), &range) }?;
+    Ok(WipeMethod::ZeroOut)
+}
+
+fn overwrite(file: &File, range: Range<u64>) -> io::Result<()> {
+    let zeroes = vec![0; ZERO_CHUNK_SIZE];
+    for off in range.clone().step_by(ZERO_CHUNK_SIZE) {
+        let len = (range.end - off).min(ZERO_CHUNK_SIZE as u64) as usize;
+        file.write_all_at(&zeroes[..len], off)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for WipeMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WipeMethod::SecureDiscard => f.write_str("secure discard"),
+            WipeMethod::ZeroOut => f.write_str("zeroing"),
+            WipeMethod::Overwrite => f.write_str("overwriting with zeroes"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_blocks_of_slice() {
+        assert_eq!(whole_blocks(&(0..8192), 4096), 0..8192);
+        assert_eq!(whole_blocks(&(512..8192), 4096), 4096..8192);
+        assert_eq!(whole_blocks(&(512..12800), 4096), 4096..12288);
+        assert!(whole_blocks(&(512..4096), 4096).is_empty());
+        assert!(whole_blocks(&(512..1024), 4096).is_empty());
+    }
+}
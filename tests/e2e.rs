@@ -0,0 +1,349 @@
+//! End to end tests, driving real ublk devices backed by temporary files.
+//!
+//! These need root, the ublk_drv module and a kernel with ublk support, so they are ignored by
+//! default. Run them with `cargo test --test e2e -- --ignored --test-threads 1`.
+
+use std::{
+    alloc::{self, Layout},
+    fs::{self, File, OpenOptions},
+    os::unix::prelude::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::OFlag;
+
+/// Size of the backing file of a test device.
+const TARGET_SIZE: u64 = 1 << 30;
+/// Size of a single IO issued by the verification workload.
+const IO_SIZE: usize = 64 << 10;
+/// Amount of IOs issued by the verification workload.
+const IO_COUNT: u64 = 256;
+/// Distance between the start of 2 IOs of the verification workload. This is not a multiple of
+/// the IO size, so the IOs cross various boundaries. All IOs fit in the target.
+const IO_STRIDE: u64 = (4 << 20) + (12 << 10);
+/// Time to wait for a device to show up after adding it.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of a sector, the unit in which a write interrupted by a crash may be torn.
+const SECTOR_SIZE: usize = 512;
+
+/// A vblock device served by a child process.
+struct Device {
+    id: i32,
+    process: Option<Child>,
+}
+
+impl Device {
+    /// Add a new device on the given target, and wait until the block device is available.
+    fn add(id: i32, target: &Path) -> Device {
+        Device::add_with(id, &[target], &[])
+    }
+
+    /// Add a new device on the given targets with extra arguments for `vblock add`, and wait
+    /// until the block device is available.
+    fn add_with(id: i32, targets: &[&Path], args: &[&str]) -> Device {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_vblock"));
+        command.arg("add").args(["--id", &id.to_string()]);
+        for target in targets {
+            command.args(["--target", target.to_str().unwrap()]);
+        }
+        let process = command
+            .args(args)
+            .arg("--insecure-test-key")
+            .spawn()
+            .expect("can spawn vblock");
+        let mut device = Device {
+            id,
+            process: Some(process),
+        };
+
+        let start = Instant::now();
+        while !device.path().exists() {
+            if let Some(status) = device.process.as_mut().unwrap().try_wait().unwrap() {
+                panic!("vblock exited with {status} before device {id} was created");
+            }
+            assert!(
+                start.elapsed() < DEVICE_TIMEOUT,
+                "device {id} did not appear in time"
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        device
+    }
+
+    /// Path of the block device.
+    fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/ublkb{}", self.id))
+    }
+
+    /// Kill the serving process without giving it a chance to clean up, simulating a crash, then
+    /// remove the device.
+    fn crash(mut self) {
+        self.remove();
+    }
+
+    fn remove(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            // SIGKILL, so nothing is cleaned up.
+            let _ = process.kill();
+            let _ = process.wait();
+            let _ = Command::new(env!("CARGO_BIN_EXE_vblock"))
+                .arg("del")
+                .args(["--id", &self.id.to_string()])
+                .status();
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// A temporary sparse file used as target, removed when dropped.
+struct TempTarget(PathBuf);
+
+impl TempTarget {
+    fn new(name: &str) -> TempTarget {
+        let path = std::env::temp_dir().join(format!("vblock-e2e-{}-{name}", std::process::id()));
+        File::create(&path).unwrap().set_len(TARGET_SIZE).unwrap();
+        TempTarget(path)
+    }
+}
+
+impl Drop for TempTarget {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// A buffer suitably aligned for direct IO.
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(size: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(size, 4096).unwrap();
+        // SAFETY: layout has a non zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        assert!(!ptr.is_null());
+        AlignedBuf { ptr, layout }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: ptr is a valid, initialized allocation of layout.size() bytes.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: ptr was allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Unique device id for a test, so concurrent test runs don't collide.
+fn device_id(test: i32) -> i32 {
+    1000 + (std::process::id() % 1000) as i32 * 10 + test
+}
+
+/// Fill a buffer with data derived from the offset it is written at and a seed.
+fn fill_pattern(buf: &mut [u8], offset: u64, seed: u64) {
+    let mut state = (offset ^ seed.rotate_left(32)) | 1;
+    for chunk in buf.chunks_mut(8) {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// Offsets written by the verification workload.
+fn workload_offsets() -> impl Iterator<Item = u64> {
+    (0..IO_COUNT).map(|i| i * IO_STRIDE)
+}
+
+/// Write the verification workload to a device, and make sure it is durable.
+fn write_workload(device: &Path, seed: u64) {
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(OFlag::O_DIRECT.bits())
+        .open(device)
+        .unwrap();
+    let mut buf = AlignedBuf::new(IO_SIZE);
+    for offset in workload_offsets() {
+        fill_pattern(buf.as_mut_slice(), offset, seed);
+        file.write_all_at(buf.as_mut_slice(), offset).unwrap();
+    }
+    file.sync_all().unwrap();
+}
+
+/// Read back the verification workload from a device, bypassing the page cache, and verify it.
+fn verify_workload(device: &Path, seed: u64) {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_DIRECT.bits())
+        .open(device)
+        .unwrap();
+    let mut buf = AlignedBuf::new(IO_SIZE);
+    let mut expected = vec![0; IO_SIZE];
+    for offset in workload_offsets() {
+        file.read_exact_at(buf.as_mut_slice(), offset).unwrap();
+        fill_pattern(&mut expected, offset, seed);
+        assert!(
+            buf.as_mut_slice() == expected,
+            "data mismatch in IO at offset {offset}"
+        );
+    }
+}
+
+#[test]
+#[ignore = "requires root and the ublk_drv module"]
+fn write_read_verify() {
+    let target = TempTarget::new("verify");
+    let device = Device::add(device_id(0), &target.0);
+
+    write_workload(&device.path(), 1);
+    verify_workload(&device.path(), 1);
+    // Overwrite everything, to make sure no stale data is returned.
+    write_workload(&device.path(), 2);
+    verify_workload(&device.path(), 2);
+}
+
+/// Seed of the `n`th write of [`write_until_error`], which rewrites the offsets of the
+/// verification workload in passes, every pass with a new seed.
+fn pass_seed(seed: u64, n: u64) -> u64 {
+    seed + 1 + n / IO_COUNT
+}
+
+/// Rewrite the verification workload on a device over and over, until a write fails. Every
+/// write is durable once it completes, and `done` counts the completed writes.
+fn write_until_error(device: &Path, seed: u64, done: &AtomicU64) {
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags((OFlag::O_DIRECT | OFlag::O_DSYNC).bits())
+        .open(device)
+        .unwrap();
+    let mut buf = AlignedBuf::new(IO_SIZE);
+    for n in 0.. {
+        let offset = (n % IO_COUNT) * IO_STRIDE;
+        fill_pattern(buf.as_mut_slice(), offset, pass_seed(seed, n));
+        if file.write_all_at(buf.as_mut_slice(), offset).is_err() {
+            return;
+        }
+        done.store(n + 1, Ordering::SeqCst);
+    }
+}
+
+/// Verify a device after it crashed during [`write_until_error`], which completed `done`
+/// writes on top of the verification workload written with `seed`. Every offset holds its last
+/// completed write. The write which was in progress during the crash may be applied partially,
+/// so every sector of it holds either the old or the new data.
+fn verify_crashed_workload(device: &Path, seed: u64, done: u64) {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_DIRECT.bits())
+        .open(device)
+        .unwrap();
+    let mut buf = AlignedBuf::new(IO_SIZE);
+    let mut old = vec![0; IO_SIZE];
+    let mut new = vec![0; IO_SIZE];
+    for (i, offset) in workload_offsets().enumerate() {
+        let i = i as u64;
+        // The last completed write to this offset, if any.
+        let old_seed = match done.checked_sub(1) {
+            Some(last) if last >= i => pass_seed(seed, last - (last - i) % IO_COUNT),
+            _ => seed,
+        };
+        file.read_exact_at(buf.as_mut_slice(), offset).unwrap();
+        fill_pattern(&mut old, offset, old_seed);
+        if done % IO_COUNT != i {
+            assert!(
+                buf.as_mut_slice() == old,
+                "data mismatch in IO at offset {offset}"
+            );
+            continue;
+        }
+        fill_pattern(&mut new, offset, pass_seed(seed, done));
+        for (sector, data) in buf.as_mut_slice().chunks(SECTOR_SIZE).enumerate() {
+            let range = sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE;
+            assert!(
+                data == &old[range.clone()] || data == &new[range],
+                "data mismatch in interrupted IO at offset {offset}, sector {sector}"
+            );
+        }
+    }
+}
+
+/// Kill a device while a write workload runs on it, then add it again on the same targets and
+/// verify that no completed write was lost.
+fn crash_during_writes(id: i32, targets: &[&Path], args: &[&str], seed: u64) {
+    let device = Device::add_with(id, targets, args);
+    write_workload(&device.path(), seed);
+
+    let done = AtomicU64::new(0);
+    let path = device.path();
+    thread::scope(|scope| {
+        let writer = scope.spawn(|| write_until_error(&path, seed, &done));
+        // Crash in the middle of a pass, once the writes are well under way.
+        let start = Instant::now();
+        while done.load(Ordering::SeqCst) < IO_COUNT + IO_COUNT / 2 {
+            assert!(!writer.is_finished(), "writes failed before the crash");
+            assert!(start.elapsed() < DEVICE_TIMEOUT, "writes are too slow");
+            thread::sleep(Duration::from_millis(1));
+        }
+        device.crash();
+    });
+
+    let device = Device::add_with(id, targets, args);
+    verify_crashed_workload(&device.path(), seed, done.load(Ordering::SeqCst));
+}
+
+#[test]
+#[ignore = "requires root and the ublk_drv module"]
+fn data_survives_crash() {
+    let target = TempTarget::new("crash");
+    crash_during_writes(device_id(1), &[&target.0], &[], 3);
+}
+
+#[test]
+#[ignore = "requires root and the ublk_drv module"]
+fn thin_data_survives_crash() {
+    let target = TempTarget::new("crash-thin");
+    crash_during_writes(device_id(3), &[&target.0], &["--thin"], 5);
+}
+
+#[test]
+#[ignore = "requires root and the ublk_drv module"]
+fn mirrored_data_survives_crash() {
+    let a = TempTarget::new("crash-mirror-a");
+    let b = TempTarget::new("crash-mirror-b");
+    crash_during_writes(device_id(4), &[&a.0, &b.0], &["--raid1"], 7);
+}
+
+#[test]
+#[ignore = "requires root and the ublk_drv module"]
+fn target_is_encrypted() {
+    let target = TempTarget::new("encrypted");
+    let device = Device::add(device_id(2), &target.0);
+    write_workload(&device.path(), 4);
+    drop(device);
+
+    let file = File::open(&target.0).unwrap();
+    let mut buf = vec![0; IO_SIZE];
+    let mut plain = vec![0; IO_SIZE];
+    for offset in workload_offsets() {
+        file.read_exact_at(&mut buf, offset).unwrap();
+        fill_pattern(&mut plain, offset, 4);
+        assert!(buf != plain, "plaintext found in target at offset {offset}");
+    }
+}